@@ -39,6 +39,10 @@ pub struct BuildSettingsBuilder{
 }
 
 
+impl Default for BuildSettingsBuilder {
+    fn default() -> Self { Self::new() }
+}
+
 impl BuildSettingsBuilder {
     pub fn new() -> Self {
         BuildSettingsBuilder{ compile_settings: None, product_path: None}
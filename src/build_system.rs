@@ -2,8 +2,10 @@ use std::marker::PhantomData;
 use std::path::{PathBuf};
 
 use crate::build_settings::{BuildSettings, BuildSettingsBuilder, PathType};
-use crate::traits::{CompileStep, LinkStep};
-use crate::CompileSystem;
+use crate::traits::{CompileTool, LinkTool, Static, TryCompileStep, TryLinkStep};
+use crate::{BuildError, CompileSystem, Incremental};
+use crate::incremental::LinkRecord;
+use crate::context::{LinkContext, Log};
 
 ///A build system with separate compile and link steps.
 ///
@@ -13,13 +15,51 @@ pub struct BuildSystem<Compiler,Linker> {
     linker: PhantomData<Linker>,
 }
 
-impl<Compiler: CompileStep,Linker: LinkStep> BuildSystem<Compiler,Linker> {
+impl<Compiler: CompileTool,Linker: LinkTool> BuildSystem<Compiler,Linker> {
+    ///Compiles/links with the compiler and linker instances, using the settings specified.
+    ///
+    /// Returns a path to the final product.
+    pub fn build_with(compiler: &Compiler, linker: &Linker, settings: &BuildSettings) -> Result<PathBuf, BuildError> {
+        let compile_settings = &settings.compile_settings;
+        let compile_results = CompileSystem::compile_all(compiler, compile_settings)?;
+        let record_path = LinkRecord::path(&compile_settings.intermediate_path, &settings.product_name);
+        let inputs = LinkRecord::fingerprint(&compile_results, &settings.product_name, &settings.product_path, compile_settings.configuration);
+        if compile_settings.incremental != Incremental::Disabled {
+            if let Some(previous) = LinkRecord::load(&record_path) {
+                if previous.inputs == inputs && previous.product.exists() {
+                    return Ok(previous.product)
+                }
+            }
+        }
+        let object_files: Vec<PathBuf> = compile_results.into_iter().map(|r| r.output).collect();
+        let context = LinkContext {
+            object_files: &object_files,
+            output_dir: &settings.product_path,
+            product_name: &settings.product_name,
+            configuration: settings.compile_settings.configuration,
+            target: &settings.compile_settings.target,
+            log: &Log::default(),
+        };
+        let product = linker.link_all(&context)?;
+        if compile_settings.incremental != Incremental::Disabled {
+            LinkRecord { inputs, product: product.clone() }.save(&record_path)?;
+        }
+        Ok(product)
+    }
+
+    ///Build with the compiler and linker instances, using no special settings.  See [BuildSystem::build_rs].
+    pub fn build_rs_with(compiler: &Compiler, linker: &Linker, exe_path: PathBuf) -> Result<PathBuf, BuildError> {
+        let settings = BuildSettingsBuilder::new().product_path(PathType::EXERelative(exe_path)).finish();
+        Self::build_with(compiler, linker, &settings)
+    }
+}
+
+impl<Compiler: TryCompileStep,Linker: TryLinkStep> BuildSystem<Compiler,Linker> {
     ///Compiles/links using the settings specified.
     ///
     /// Returns a path to the final product.
-    pub fn build(settings: &BuildSettings) -> PathBuf {
-        let compile_results = CompileSystem::<Compiler>::compile_all(&settings.compile_settings);
-        Linker::link_all(&compile_results, &settings.product_path,&settings.product_name,  &settings.compile_settings.configuration)
+    pub fn build(settings: &BuildSettings) -> Result<PathBuf, BuildError> {
+        BuildSystem::build_with(&Static::<Compiler>::new(), &Static::<Linker>::new(), settings)
     }
 
     ///Build using no special settings.  Usually the entrypoint from `build.rs`
@@ -27,8 +67,111 @@ impl<Compiler: CompileStep,Linker: LinkStep> BuildSystem<Compiler,Linker> {
     /// `exe_path`: The path, relative to the built exe file, where the output should be located.
     /// For example if your target is built in `target\debug\my.exe` and `exe_path` is `assets\product.dll`, the final location will be
     /// `target\debug\assets\product.dll`.  The intermediate directories will be created if they do not already exist.
-    pub fn build_rs(exe_path: PathBuf) -> PathBuf {
+    pub fn build_rs(exe_path: PathBuf) -> Result<PathBuf, BuildError> {
         let settings = BuildSettingsBuilder::new().product_path(PathType::EXERelative(exe_path)).finish();
         Self::build(&settings)
     }
 }
+
+#[test] fn build_with_instances() {
+    use crate::{CompileContext, CompileSettingsBuilder, Configuration, SourceFileStrategy};
+    struct PrefixCompiler { prefix: String }
+    impl CompileTool for PrefixCompiler {
+        fn source_file_extension(&self) -> &str { "txt" }
+        fn compile_one(&self, context: &CompileContext) -> Result<PathBuf, BuildError> {
+            let output = context.output_dir.join(context.source.file_name().unwrap());
+            std::fs::write(&output, format!("{}{}", self.prefix, std::fs::read_to_string(context.source).unwrap())).unwrap();
+            std::fs::write(context.dependency_path, format!("{}: {}", output.display(), context.source.display())).unwrap();
+            Ok(output)
+        }
+    }
+    struct ConcatLinker { separator: &'static str }
+    impl LinkTool for ConcatLinker {
+        fn link_all(&self, context: &LinkContext) -> Result<PathBuf, BuildError> {
+            let contents: Vec<_> = context.object_files.iter().map(|f| std::fs::read_to_string(f).unwrap()).collect();
+            let product = context.output_dir.join(context.product_name);
+            std::fs::write(&product, contents.join(self.separator)).unwrap();
+            Ok(product)
+        }
+    }
+    let mut dir = std::env::temp_dir();
+    dir.push("buildkit-build-with-instances");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("product")).unwrap();
+    let sources: Vec<PathBuf> = ["a.txt", "b.txt"].iter().map(|n| dir.join(n)).collect();
+    std::fs::write(&sources[0], "a").unwrap();
+    std::fs::write(&sources[1], "b").unwrap();
+    let compile_settings = CompileSettingsBuilder::new()
+        .source_strategy(SourceFileStrategy::SourceFiles(sources))
+        .intermediate_path(PathType::Exact(dir.join("intermediate")))
+        .configuration(Configuration::Debug)
+        .finish();
+    let settings = BuildSettingsBuilder::new()
+        .compile_settings(compile_settings)
+        .product_path(PathType::Exact(dir.join("product")))
+        .finish();
+    let compiler = PrefixCompiler { prefix: "compiled ".to_owned() };
+    let product = BuildSystem::build_with(&compiler, &ConcatLinker { separator: ", " }, &settings).unwrap();
+    assert_eq!(std::fs::read_to_string(product).unwrap(), "compiled a, compiled b");
+}
+
+#[test] fn skip_link() {
+    use crate::{Incremental, SourceFileStrategy};
+    use crate::test_support::{CopyCompiler, CountingLinker, build_settings, compile_settings, set_modified, test_dir, write_sources};
+    let dir = test_dir("skip-link");
+    let sources = write_sources(&dir, &["a.txt", "b.txt"]);
+    let settings = build_settings(&dir, compile_settings(&dir, SourceFileStrategy::SourceFiles(sources.clone())).incremental(Incremental::Timestamps).finish());
+    let (compiler, linker) = (CopyCompiler::new(), CountingLinker::new());
+    let product = BuildSystem::build_with(&compiler, &linker, &settings).unwrap();
+    assert_eq!(linker.links(), 1);
+    assert_eq!(BuildSystem::build_with(&compiler, &linker, &settings).unwrap(), product);
+    assert_eq!(linker.links(), 1);
+    //a missing product is linked again
+    std::fs::remove_file(&product).unwrap();
+    BuildSystem::build_with(&compiler, &linker, &settings).unwrap();
+    assert_eq!(linker.links(), 2);
+    //so is a changed object
+    set_modified(&sources[0], std::time::SystemTime::now() + std::time::Duration::from_secs(10));
+    BuildSystem::build_with(&compiler, &linker, &settings).unwrap();
+    assert_eq!(linker.links(), 3);
+}
+
+#[test] fn restat() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::{CompileContext, Incremental, SourceFileStrategy};
+    use crate::test_support::{CountingLinker, build_settings, compile_settings, set_modified, test_dir, write_sources};
+    ///Drops `#` comments
+    struct StripCompiler { compiles: AtomicUsize }
+    impl CompileTool for StripCompiler {
+        fn source_file_extension(&self) -> &str { "txt" }
+        fn compile_one(&self, context: &CompileContext) -> Result<PathBuf, BuildError> {
+            self.compiles.fetch_add(1, Ordering::Relaxed);
+            let output = context.intermediate_file("out")?;
+            let source = std::fs::read_to_string(context.source).unwrap();
+            let stripped: Vec<_> = source.lines().filter(|l| !l.starts_with('#')).collect();
+            std::fs::write(&output, stripped.join("\n")).unwrap();
+            std::fs::write(context.dependency_path, format!("{}: {}", output.display(), context.source.display())).unwrap();
+            Ok(output)
+        }
+    }
+    let dir = test_dir("restat");
+    let source = write_sources(&dir, &["a.txt"]).remove(0);
+    std::fs::write(&source, "# comment\ncode").unwrap();
+    let settings = build_settings(&dir, compile_settings(&dir, SourceFileStrategy::SourceFiles(vec![source.clone()])).incremental(Incremental::Timestamps).restat(true).finish());
+    let compiler = StripCompiler { compiles: AtomicUsize::new(0) };
+    let linker = CountingLinker::new();
+    BuildSystem::build_with(&compiler, &linker, &settings).unwrap();
+    //editing only the comment compiles again, but the output is the same, so we don't link
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+    std::fs::write(&source, "# edited comment\ncode").unwrap();
+    set_modified(&source, later);
+    BuildSystem::build_with(&compiler, &linker, &settings).unwrap();
+    assert_eq!(compiler.compiles.load(Ordering::Relaxed), 2);
+    assert_eq!(linker.links(), 1);
+    //editing the code does
+    std::fs::write(&source, "# edited comment\nnew code").unwrap();
+    set_modified(&source, later + std::time::Duration::from_secs(10));
+    BuildSystem::build_with(&compiler, &linker, &settings).unwrap();
+    assert_eq!(compiler.compiles.load(Ordering::Relaxed), 3);
+    assert_eq!(linker.links(), 2);
+}
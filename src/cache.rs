@@ -0,0 +1,295 @@
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use crate::{BuildError, TargetInfo};
+use crate::content_hash::{ContentHash, ContentHasher, FileHashes};
+use crate::incremental::{escape, unescape_path};
+
+///Names a cache directory when [crate::CompileSettingsBuilder::cache_dir] isn't called.
+pub(crate) const CACHE_DIR_ENV: &str = "BUILDKIT_CACHE_DIR";
+///Default for [crate::CompileSettingsBuilder::cache_max_size]
+pub(crate) const DEFAULT_MAX_SIZE: u64 = 1 << 30;
+
+const MANIFEST_MAGIC: &str = "buildkit-cache";
+///Bump this whenever the cache format changes.  Caches from other versions are ignored.
+const MANIFEST_VERSION: u32 = 1;
+///How many variants (e.g. different header contents) to remember for one sourcefile
+const MAX_ENTRIES: usize = 16;
+
+///Where the artifact cache lives, and how big it may get.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub(crate) struct CacheSettings {
+    pub(crate) dir: PathBuf,
+    pub(crate) max_size: u64,
+}
+
+///How often the artifact cache had an output we could reuse.
+///
+/// See [crate::CompileSettingsBuilder::cache_dir].
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+#[non_exhaustive]
+pub struct CacheStats {
+    ///Sourcefiles we restored from the cache instead of compiling
+    pub hits: u64,
+    ///Sourcefiles we had to compile
+    pub misses: u64,
+}
+
+impl CacheStats {
+    ///Totals for every build that has used the cache in `cache_dir`.
+    ///
+    /// Builds that run at the same time may miscount.
+    pub fn load(cache_dir: &Path) -> CacheStats {
+        let mut stats = CacheStats::default();
+        let Ok(contents) = std::fs::read_to_string(cache_dir.join("stats")) else { return stats };
+        for line in contents.lines() {
+            match line.split_once('\t') {
+                Some(("hits", n)) => stats.hits = n.parse().unwrap_or(0),
+                Some(("misses", n)) => stats.misses = n.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+        stats
+    }
+}
+
+///An output and the dependencies it was compiled with
+#[derive(Debug)]
+pub(crate) struct CachedOutput {
+    pub(crate) output: PathBuf,
+    pub(crate) dependencies: Vec<PathBuf>,
+    pub(crate) output_hash: ContentHash,
+}
+
+/**
+A directory of outputs shared between builds, e.g. of different crates or worktrees.
+
+Like ccache's direct mode, a key made of everything we know before compiling (the sourcefile, settings and step)
+names a manifest.  The manifest lists the dependencies discovered on earlier compiles, with their hashes, and the
+output each combination produced.  Outputs are stored by the hash of their contents.
+
+Paths inside the sourcefile's root are stored relative to it, and outputs relative to the output directory,
+so builds in different places can share.
+*/
+pub(crate) struct ArtifactCache<'a> {
+    settings: &'a CacheSettings,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stored: AtomicBool,
+}
+
+impl<'a> ArtifactCache<'a> {
+    pub(crate) fn new(settings: &'a CacheSettings) -> Self {
+        ArtifactCache { settings, hits: AtomicU64::new(0), misses: AtomicU64::new(0), stored: AtomicBool::new(false) }
+    }
+
+    ///The key for compiling `source`, or `None` if it can't be cached.
+    pub(crate) fn key(identity: &str, settings: ContentHash, target: &TargetInfo, source: &Path, root: Option<&Path>, hashes: &FileHashes) -> Option<ContentHash> {
+        let mut hasher = ContentHasher::new();
+        hasher.write_field(identity.as_bytes());
+        hasher.write_field(settings.to_string().as_bytes());
+        hasher.write_field(format!("{:?}", target.triple).as_bytes());
+        //the output path mirrors the sourcefile's, so identical sourcefiles in different places need their own entries
+        hasher.write_field(portable(source, root)?.as_bytes());
+        hasher.write_field(hashes.get(source)?.to_string().as_bytes());
+        Some(hasher.finish())
+    }
+
+    fn manifest_path(&self, key: ContentHash) -> PathBuf {
+        self.settings.dir.join("manifests").join(key.to_string())
+    }
+
+    fn object_path(&self, hash: ContentHash) -> PathBuf {
+        self.settings.dir.join("objects").join(hash.to_string())
+    }
+
+    ///Copies a previous output into `output_dir`, if one was compiled from the same inputs.
+    pub(crate) fn restore(&self, key: ContentHash, root: Option<&Path>, output_dir: &Path, hashes: &FileHashes) -> Option<CachedOutput> {
+        let restored = self.find(key, root, output_dir, hashes);
+        match restored {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        restored
+    }
+
+    fn find(&self, key: ContentHash, root: Option<&Path>, output_dir: &Path, hashes: &FileHashes) -> Option<CachedOutput> {
+        let manifest_path = self.manifest_path(key);
+        let entries = read_manifest(&manifest_path);
+        let entry = entries.iter().find(|entry| {
+            entry.dependencies.iter().all(|(path, hash)| hashes.get(&rebase(path, root)) == Some(*hash))
+        })?;
+        let object = self.object_path(entry.output_hash);
+        let output = output_dir.join(&entry.output);
+        if let Some(parent) = output.parent() {
+            std::fs::create_dir_all(parent).ok()?;
+        }
+        std::fs::copy(&object, &output).ok()?;
+        //garbage collection removes the least recently used files first
+        touch(&object);
+        touch(&manifest_path);
+        Some(CachedOutput {
+            output,
+            dependencies: entry.dependencies.iter().map(|(path, _)| rebase(path, root)).collect(),
+            output_hash: entry.output_hash,
+        })
+    }
+
+    ///Remembers an output we just compiled.  Outputs outside `output_dir`, or with paths we can't write down, aren't cached.
+    pub(crate) fn store(&self, key: ContentHash, root: Option<&Path>, output_dir: &Path, output: &Path, dependencies: &[PathBuf], hashes: &FileHashes) -> Result<(), BuildError> {
+        let Some(relative) = output.strip_prefix(output_dir).ok().filter(|r| is_normal(r)).and_then(Path::to_str) else { return Ok(()) };
+        let Some(output_hash) = ContentHash::of_file(output) else { return Ok(()) };
+        let mut entry = Entry { output: PathBuf::from(relative), output_hash, dependencies: Vec::new() };
+        for dependency in dependencies {
+            let (Some(path), Some(hash)) = (portable(dependency, root), hashes.get(dependency)) else { return Ok(()) };
+            entry.dependencies.push((path, hash));
+        }
+        let object = self.object_path(entry.output_hash);
+        if !object.exists() {
+            write_atomically(&object, |temp| std::fs::copy(output, temp).map(|_| ()))?;
+        }
+        let manifest_path = self.manifest_path(key);
+        let mut entries = read_manifest(&manifest_path);
+        entries.retain(|e| e.dependencies != entry.dependencies);
+        entries.insert(0, entry);
+        entries.truncate(MAX_ENTRIES);
+        write_atomically(&manifest_path, |temp| std::fs::write(temp, write_manifest(&entries)))?;
+        self.stored.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    ///Updates the statistics and, if we added anything, removes the least recently used files until the cache fits.
+    ///
+    /// Returns the statistics for this build.
+    pub(crate) fn finish(&self) -> Result<CacheStats, BuildError> {
+        let build = CacheStats { hits: self.hits.load(Ordering::Relaxed), misses: self.misses.load(Ordering::Relaxed) };
+        if build == CacheStats::default() { return Ok(build) }
+        let mut total = CacheStats::load(&self.settings.dir);
+        total.hits += build.hits;
+        total.misses += build.misses;
+        let stats_path = self.settings.dir.join("stats");
+        write_atomically(&stats_path, |temp| std::fs::write(temp, format!("hits\t{}\nmisses\t{}\n", total.hits, total.misses)))?;
+        if self.stored.load(Ordering::Relaxed) {
+            self.collect_garbage()?;
+        }
+        Ok(build)
+    }
+
+    fn collect_garbage(&self) -> Result<(), BuildError> {
+        let mut files = Vec::new();
+        for dir in ["objects", "manifests"] {
+            let dir = self.settings.dir.join(dir);
+            let Ok(items) = std::fs::read_dir(&dir) else { continue };
+            for item in items {
+                let item = item.map_err(|error| BuildError::Io { path: dir.clone(), error })?;
+                //another build may be removing files too
+                let Ok(metadata) = item.metadata() else { continue };
+                files.push((metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), metadata.len(), item.path()));
+            }
+        }
+        let mut size: u64 = files.iter().map(|(_, len, _)| len).sum();
+        files.sort();
+        for (_, len, path) in files {
+            if size <= self.settings.max_size { break }
+            //a manifest that names a removed object is just a miss
+            let _ = std::fs::remove_file(&path);
+            size -= len;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug,PartialEq,Eq)]
+struct Entry {
+    ///Relative to the output directory
+    output: PathBuf,
+    output_hash: ContentHash,
+    ///See [portable]
+    dependencies: Vec<(String, ContentHash)>,
+}
+
+fn read_manifest(path: &Path) -> Vec<Entry> {
+    std::fs::read_to_string(path).ok().and_then(|c| parse_manifest(&c)).unwrap_or_default()
+}
+
+fn parse_manifest(contents: &str) -> Option<Vec<Entry>> {
+    let mut lines = contents.lines();
+    if lines.next()? != format!("{}\t{}", MANIFEST_MAGIC, MANIFEST_VERSION) {
+        return None
+    }
+    let mut entries: Vec<Entry> = Vec::new();
+    for line in lines {
+        match line.split('\t').collect::<Vec<_>>().as_slice() {
+            ["entry", hash, output] => entries.push(Entry { output: unescape_path(output)?, output_hash: ContentHash::parse(hash)?, dependencies: Vec::new() }),
+            ["dependency", hash, path] => {
+                let path = unescape_path(path)?.to_str()?.to_owned();
+                entries.last_mut()?.dependencies.push((path, ContentHash::parse(hash)?));
+            }
+            _ => return None
+        }
+    }
+    Some(entries)
+}
+
+fn write_manifest(entries: &[Entry]) -> String {
+    let mut contents = format!("{}\t{}\n", MANIFEST_MAGIC, MANIFEST_VERSION);
+    for entry in entries {
+        //store only writes entries whose paths are valid unicode
+        contents.push_str(&format!("entry\t{}\t{}\n", entry.output_hash, escape(entry.output.to_str().unwrap())));
+        for (path, hash) in &entry.dependencies {
+            contents.push_str(&format!("dependency\t{}\t{}\n", hash, escape(path)));
+        }
+    }
+    contents
+}
+
+///Writes `path` by way of a temporary file, so builds running at the same time never see half a file.
+fn write_atomically(path: &Path, write: impl FnOnce(&Path) -> std::io::Result<()>) -> Result<(), BuildError> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let dir = path.parent().unwrap();
+    std::fs::create_dir_all(dir).map_err(|error| BuildError::Io { path: dir.to_path_buf(), error })?;
+    let temp = dir.join(format!(".tmp-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let result = write(&temp).and_then(|_| std::fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result.map_err(|error| BuildError::Io { path: path.to_path_buf(), error })
+}
+
+fn touch(path: &Path) {
+    if let Ok(file) = std::fs::File::options().write(true).open(path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+fn is_normal(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+///`path` relative to `root` if it is inside it, otherwise absolute.
+fn portable(path: &Path, root: Option<&Path>) -> Option<String> {
+    match root.and_then(|root| path.strip_prefix(root).ok()).filter(|r| is_normal(r)) {
+        Some(relative) => relative.to_str().map(|r| r.to_owned()),
+        None => path.to_str().map(|p| p.to_owned()),
+    }
+}
+
+///Undoes [portable]
+fn rebase(path: &str, root: Option<&Path>) -> PathBuf {
+    match root {
+        Some(root) if Path::new(path).is_relative() => root.join(path),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[test] fn manifest_roundtrip() {
+    let entries = vec![
+        Entry { output: PathBuf::from("ui/a\tb.spv"), output_hash: ContentHash::parse("0123456789abcdef0123456789abcdef").unwrap(),
+            dependencies: vec![("ui/a\tb.frag".to_owned(), ContentHash::parse("fedcba9876543210fedcba9876543210").unwrap())] },
+        Entry { output: PathBuf::from("c.spv"), output_hash: ContentHash::parse("00000000000000000000000000000001").unwrap(), dependencies: Vec::new() },
+    ];
+    assert_eq!(parse_manifest(&write_manifest(&entries)).unwrap(), entries);
+    assert_eq!(rebase(&portable(Path::new("/a/src/b.frag"), Some(Path::new("/a"))).unwrap(), Some(Path::new("/c"))), Path::new("/c/src/b.frag"));
+    assert_eq!(rebase(&portable(Path::new("/usr/include/x.h"), Some(Path::new("/a"))).unwrap(), Some(Path::new("/c"))), Path::new("/usr/include/x.h"));
+}
@@ -0,0 +1,61 @@
+use std::process::{Command, Output};
+
+use crate::{BuildError, StepError, StepKind};
+
+/**
+Runs a tool on behalf of a [crate::TryCompileStep] or [crate::TryLinkStep].
+
+* Captures the tool's output, which is returned on success.
+* Forwards each line the tool prints to stderr as a `cargo:warning`, so it shows up in cargo's output.
+* If the tool can't be started or exits unsuccessfully, returns a [BuildError::Step] with the full command line,
+  exit status and output.  Compile steps may leave the sourcefile out; buildkit fills it in.
+
+```no_run
+use buildkit::{run_command,StepKind};
+let output = run_command(std::process::Command::new("glslc").arg("shader.frag"), StepKind::Compile)?;
+# Ok::<(),buildkit::BuildError>(())
+```
+*/
+pub fn run_command(command: &mut Command, kind: StepKind) -> Result<Output, BuildError> {
+    let output = command.output().map_err(|e| {
+        StepError::new(kind, format!("Problem running {:?}: {}", command.get_program(), e)).with_command(command)
+    })?;
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        if !line.trim().is_empty() {
+            println!("cargo:warning={}", line);
+        }
+    }
+    if output.status.success() {
+        Ok(output)
+    }
+    else {
+        Err(StepError::new(kind, format!("{:?} failed", command.get_program())).with_command(command).with_output(&output).into())
+    }
+}
+
+///Formats the command the way you might type it in a shell
+pub(crate) fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program()).chain(command.get_args()).map(|arg| {
+        let arg = arg.to_string_lossy();
+        if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+            format!("{:?}", arg)
+        }
+        else {
+            arg.into_owned()
+        }
+    }).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(unix)]
+#[test] fn failing_command() {
+    let mut command = Command::new("sh");
+    command.args(["-c", "echo problem >&2; exit 3"]);
+    match run_command(&mut command, StepKind::Link) {
+        Err(BuildError::Step(step)) => {
+            assert_eq!(step.command.as_deref(), Some(r#"sh -c "echo problem >&2; exit 3""#));
+            assert_eq!(step.status.unwrap().code(), Some(3));
+            assert_eq!(step.stderr, "problem\n");
+        }
+        other => panic!("Expected a step error, got {:?}", other)
+    }
+}
@@ -1,38 +1,106 @@
-use std::path::{ PathBuf};
-use crate::{CompileStep, Configuration, PathType};
+use std::path::{Path, PathBuf};
+use crate::{BuildError, Configuration, Incremental, PathType, SymlinkPolicy, TargetInfo};
 use std::str::FromStr;
-use crate::compile_system::dir_walk;
+use crate::walk::{dir_walk, has_extension, WalkOptions};
+use crate::glob::Glob;
+use crate::cache::{CacheSettings, CACHE_DIR_ENV, DEFAULT_MAX_SIZE};
 
 ///How to find sourcefiles for compiling
 #[derive(Clone)]
 pub enum SourceFileStrategy {
     ///Use exactly the sourcefiles specified.
+    ///
+    /// Relative paths are relative to the manifest directory, and their intermediate files mirror them, e.g. `ui/b.vert` compiles into `<intermediate>/ui`.
     SourceFiles(Vec<PathBuf>),
     ///Search recursively in these paths, starting from the manifest directory. e.g. payload like "src/"
     ///
     /// Note that if this path is absolute, we will search the absolute path instead.
-    SearchFromManifest(Vec<PathBuf>)
+    SearchFromManifest(Vec<PathBuf>),
+    ///Use the files matching any `include` glob and no `exclude` glob, e.g. `shaders/**/*.hlsl`.
+    ///
+    /// Relative patterns start from the manifest directory.  Patterns support `*`, `?`, `[a-z]`, `[!a-z]`,
+    /// and `**` for any number of directories.  Matching files are used regardless of their extension,
+    /// and are sorted so builds are reproducible.  It is an error if the directory a pattern starts from doesn't exist.
+    Glob {
+        include: Vec<String>,
+        exclude: Vec<String>,
+    },
+}
+
+///What a [SourceFileStrategy] found
+#[derive(Debug,Default)]
+pub(crate) struct ResolvedSources {
+    pub(crate) files: Vec<SourceFile>,
+    ///Other files that decided what we found, e.g. ignore files.  Changing these should rerun the build.
+    pub(crate) watched: Vec<PathBuf>,
+}
+
+///A sourcefile found by a [SourceFileStrategy]
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub(crate) struct SourceFile {
+    pub(crate) path: PathBuf,
+    ///The directory we found it in, which its intermediate files mirror
+    pub(crate) root: Option<PathBuf>,
 }
 
 impl SourceFileStrategy {
-    pub(crate) fn resolve<C: CompileStep>(&self) -> Vec<PathBuf> {
+    pub(crate) fn resolve(&self, extensions: &[&str], options: &WalkOptions) -> Result<ResolvedSources, BuildError> {
+        let mut resolved = ResolvedSources::default();
         match self {
-            SourceFileStrategy::SourceFiles(paths) => paths.into_iter().map(|f| f.clone()).collect(),
+            //sourcefiles in the crate mirror the crate's layout
+            SourceFileStrategy::SourceFiles(paths) => {
+                let manifest = std::env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from);
+                resolved.files = paths.iter().map(|path| {
+                    //cargo runs build.rs from the manifest directory, so relative paths already mirror the crate's layout
+                    let root = if path.is_relative() { Some(PathBuf::new()) } else { manifest.clone() };
+                    SourceFile { path: path.clone(), root }
+                }).collect();
+            }
             SourceFileStrategy::SearchFromManifest(manifest_paths) => {
-                let manifest_string = std::env::var("CARGO_MANIFEST_DIR").unwrap();
-                let m_path = PathBuf::from_str(&manifest_string).unwrap();
-                let mut vec = Vec::new();
+                let m_path = manifest_dir();
 
                 for path in manifest_paths {
                     let mut new_path = m_path.clone();
                     new_path.push(path);
-                    dir_walk(&new_path, C::SOURCE_FILE_EXTENSION, &mut vec);
+                    let mut found = Vec::new();
+                    dir_walk(&new_path, &|p| has_extension(p, extensions), options, &mut found, &mut resolved.watched)?;
+                    resolved.files.extend(found.into_iter().map(|path| SourceFile { path, root: Some(new_path.clone()) }));
                 }
-                vec
+            }
+            SourceFileStrategy::Glob { include, exclude } => {
+                let m_path = manifest_dir();
+                let exclude: Vec<Glob> = exclude.iter().map(|pattern| Glob::new(&m_path, pattern)).collect();
+                let vec = &mut resolved.files;
+                for pattern in include {
+                    let include = Glob::new(&m_path, pattern);
+                    let filter = |p: &Path| include.matches(p) && !exclude.iter().any(|e| e.matches(p));
+                    let root = include.root();
+                    //a missing root is probably a typo, so report it rather than matching nothing
+                    let metadata = std::fs::metadata(root).map_err(|error| BuildError::Io { path: root.to_path_buf(), error })?;
+                    if metadata.is_dir() {
+                        let mut found = Vec::new();
+                        dir_walk(root, &filter, options, &mut found, &mut resolved.watched)?;
+                        vec.extend(found.into_iter().map(|path| SourceFile { path, root: Some(root.to_path_buf()) }));
+                    }
+                    //a pattern without wildcards names a single file
+                    else if filter(root) {
+                        vec.push(SourceFile { path: root.to_path_buf(), root: root.parent().map(Path::to_path_buf) });
+                    }
+                }
+                //stable, so a file matched by several patterns keeps the root of the first
+                vec.sort_by(|a, b| a.path.cmp(&b.path));
+                vec.dedup_by(|a, b| a.path == b.path);
             }
         }
+        Ok(resolved)
     }
 }
+
+fn manifest_dir() -> PathBuf {
+    let manifest_string = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    PathBuf::from_str(&manifest_string).unwrap()
+}
+
 #[derive(Clone)]
 pub struct CompileSettings {
     //Will scan this path for sourcefiles
@@ -44,12 +112,26 @@ pub struct CompileSettings {
     /// Usually, (and by default), this is the intermediate directory.
     /// However, if you are using a compile step with no link step, it might make
     /// sense to send the object files to a products directory, away from any intermediates, which
-    /// may include the dependency file.
+    /// may include the dependency files.
     pub(crate) product_path: PathBuf,
     ///Whether debug/release
     pub(crate) configuration: Configuration,
     ///Pass these flags to the compiler.
     pub(crate) flags: Vec<String>,
+    ///Maximum number of [crate::CompileStep::compile_one] calls to run at once.
+    pub(crate) jobs: usize,
+    ///How to decide which sourcefiles need compiling.
+    pub(crate) incremental: Incremental,
+    ///Whether to keep compiling after a sourcefile fails.
+    pub(crate) keep_going: bool,
+    ///Whether to compare outputs with the previous build, so unchanged outputs don't cause a link.
+    pub(crate) restat: bool,
+    ///Shared cache of outputs, if any
+    pub(crate) cache: Option<CacheSettings>,
+    ///The platform being built for
+    pub(crate) target: TargetInfo,
+    ///How to search directories for sourcefiles
+    pub(crate) walk_options: WalkOptions,
 }
 
 #[derive(Clone)]
@@ -59,6 +141,17 @@ pub struct CompileSettingsBuilder {
     configuration: Option<Configuration>,
     product_path: Option<PathType>,
     flags: Vec<String>,
+    jobs: Option<usize>,
+    incremental: Incremental,
+    keep_going: bool,
+    restat: bool,
+    cache_dir: Option<PathBuf>,
+    cache_max_size: u64,
+    walk_options: WalkOptions,
+}
+
+impl Default for CompileSettingsBuilder {
+    fn default() -> Self { Self::new() }
 }
 
 impl CompileSettingsBuilder {
@@ -69,6 +162,13 @@ impl CompileSettingsBuilder {
             configuration: None,
             product_path: None,
             flags: Vec::new(),
+            jobs: None,
+            incremental: Incremental::Disabled,
+            keep_going: false,
+            restat: false,
+            cache_dir: None,
+            cache_max_size: DEFAULT_MAX_SIZE,
+            walk_options: WalkOptions::default(),
         }
     }
     pub fn source_strategy(&mut self,strategy: SourceFileStrategy) -> &mut Self {
@@ -127,12 +227,33 @@ impl CompileSettingsBuilder {
                 }
             }
         };
+        let jobs = match self.jobs {
+            Some(jobs) => jobs,
+            None => {
+                //cargo tells build scripts how many jobs it is using
+                match std::env::var("NUM_JOBS").ok().and_then(|j| j.parse::<usize>().ok()) {
+                    Some(jobs) if jobs > 0 => jobs,
+                    _ => std::thread::available_parallelism().map(|p| p.get()).unwrap_or(1)
+                }
+            }
+        };
+        let cache = match &self.cache_dir {
+            Some(dir) => Some(dir.clone()),
+            None => std::env::var_os(CACHE_DIR_ENV).filter(|d| !d.is_empty()).map(PathBuf::from),
+        }.map(|dir| CacheSettings { dir, max_size: self.cache_max_size });
         CompileSettings {
             source_strategy,
             intermediate_path,
             configuration,
             product_path,
             flags: self.flags.clone(),
+            jobs,
+            incremental: self.incremental,
+            keep_going: self.keep_going,
+            restat: self.restat,
+            cache,
+            target: TargetInfo::from_env(),
+            walk_options: self.walk_options.clone(),
         }
     }
     ///Set compiler flags.
@@ -140,6 +261,90 @@ impl CompileSettingsBuilder {
         self.flags = flags;
         self
     }
+    ///Set the maximum number of sourcefiles to compile concurrently.
+    ///
+    /// By default, we use `NUM_JOBS` if set (as it is in `build.rs`), otherwise the number of CPUs.
+    /// When cargo provides a jobserver, each concurrent compile also waits for a job token, so we share
+    /// the machine with the rest of the build instead of oversubscribing it.
+    ///
+    /// Compiled files are returned in the same order regardless of this setting.
+    pub fn jobs(&mut self, jobs: usize) -> &mut Self {
+        assert!(jobs > 0, "Must allow at least 1 job");
+        self.jobs = Some(jobs);
+        self
+    }
+    ///Skip compiling sourcefiles that have not changed since the previous build.
+    ///
+    /// Whether or not this is enabled, outputs of sourcefiles that were removed since the previous build are deleted.
+    ///
+    /// A [crate::BuildSystem] also skips the link step when no object file changed and the product still exists.
+    ///
+    /// Defaults to [Incremental::Disabled].  What we learn about each build is stored in the intermediate directory.
+    pub fn incremental(&mut self, incremental: Incremental) -> &mut Self {
+        self.incremental = incremental;
+        self
+    }
+    ///Whether to compile every sourcefile even after some fail, so all the failures are reported together
+    /// as a [crate::BuildError::Multiple].  Nothing is linked if anything failed.
+    ///
+    /// Defaults to `false`, which stops at the first failure and returns only that error, even if other jobs failed at the same time.
+    pub fn keep_going(&mut self, keep_going: bool) -> &mut Self {
+        self.keep_going = keep_going;
+        self
+    }
+    ///Hash each output after compiling it, so that an output that comes out the same as on the previous build,
+    /// e.g. because only a comment changed, doesn't cause the link step to run.
+    ///
+    /// This is always on with [Incremental::ContentHash], and has no effect with [Incremental::Disabled].  Defaults to `false`.
+    pub fn restat(&mut self, restat: bool) -> &mut Self {
+        self.restat = restat;
+        self
+    }
+    ///Share outputs through a cache in this directory, e.g. between crates or worktrees.
+    ///
+    /// Before compiling a sourcefile, we look for an output compiled from the same sourcefile, dependencies, flags, configuration,
+    /// target and [crate::CompileTool::cache_identity], and copy it instead.  Without this, the `BUILDKIT_CACHE_DIR` environment
+    /// variable is used if set; otherwise there is no cache.
+    ///
+    /// See also [CompileSettingsBuilder::cache_max_size] and [crate::CacheStats].
+    pub fn cache_dir(&mut self, dir: PathBuf) -> &mut Self {
+        self.cache_dir = Some(dir);
+        self
+    }
+    ///When the cache grows past this many bytes, the least recently used files are removed.  Defaults to 1 GiB.
+    pub fn cache_max_size(&mut self, bytes: u64) -> &mut Self {
+        self.cache_max_size = bytes;
+        self
+    }
+    ///When searching directories for sourcefiles, skip paths listed in `.gitignore` and `.buildkitignore` files.
+    ///
+    /// These use `.gitignore` syntax.  Like git, ignore files in parent directories apply, up to the root of the repository.
+    /// Defaults to `false`.
+    pub fn respect_ignore_files(&mut self, respect: bool) -> &mut Self {
+        self.walk_options.ignore_files = respect;
+        self
+    }
+    ///When searching directories for sourcefiles, also search hidden directories (those starting with `.`, like `.git`).
+    ///
+    /// Defaults to `false`.
+    pub fn include_hidden(&mut self, include: bool) -> &mut Self {
+        self.walk_options.include_hidden = include;
+        self
+    }
+    ///What to do with symlinks found when searching directories for sourcefiles.
+    ///
+    /// Defaults to [SymlinkPolicy::Follow].
+    pub fn symlinks(&mut self, policy: SymlinkPolicy) -> &mut Self {
+        self.walk_options.symlinks = policy;
+        self
+    }
+    ///How many levels of subdirectories to search below each search path.  `0` only searches the path itself.
+    ///
+    /// By default, there is no limit.
+    pub fn max_depth(&mut self, depth: usize) -> &mut Self {
+        self.walk_options.max_depth = Some(depth);
+        self
+    }
     pub fn finish(&mut self) -> CompileSettings {
         //public version is non-link
         self._finish(false)
@@ -148,6 +353,7 @@ impl CompileSettingsBuilder {
 
 #[test] fn source_walk() {
     use std::path::Path;
+    use crate::CompileStep;
     struct YamlCompiler;
     impl CompileStep for YamlCompiler {
         const SOURCE_FILE_EXTENSION: &'static str = "yaml";
@@ -155,6 +361,34 @@ impl CompileSettingsBuilder {
             todo!()
         }
     }
-    let s = SourceFileStrategy::SearchFromManifest(vec![PathBuf::from_str("src").unwrap()]).resolve::<YamlCompiler>();
+    let s = SourceFileStrategy::SearchFromManifest(vec![PathBuf::from_str("src").unwrap()]).resolve(YamlCompiler::SOURCE_FILE_EXTENSIONS, &WalkOptions::default()).unwrap().files;
     assert_eq!(s.len(), 0); //no yaml files in our build directory
-}
\ No newline at end of file
+}
+#[test] fn multiple_extensions() {
+    let s = SourceFileStrategy::SearchFromManifest(vec![PathBuf::from_str("src").unwrap()]).resolve(&["yaml", "rs"], &WalkOptions::default()).unwrap().files;
+    assert!(s.iter().any(|s| s.path.ends_with("lib.rs")));
+    assert!(s.iter().all(|s| s.path.extension().unwrap() == "rs"));
+}
+
+#[test] fn glob_strategy() {
+    let s = SourceFileStrategy::Glob {
+        include: vec!["src/**/*.rs".to_owned(), "Cargo.toml".to_owned()],
+        exclude: vec!["src/lib.rs".to_owned(), "src/[c]*".to_owned()],
+    }.resolve(&[], &WalkOptions::default()).unwrap().files.into_iter().map(|s| s.path).collect::<Vec<_>>();
+    let manifest = manifest_dir();
+    assert!(s.contains(&manifest.join("Cargo.toml")));
+    assert!(s.contains(&manifest.join("src").join("glob.rs")));
+    assert!(!s.contains(&manifest.join("src").join("lib.rs")));
+    assert!(!s.iter().any(|p| p.file_name().unwrap().to_str().unwrap().starts_with('c')));
+    let mut sorted = s.clone();
+    sorted.sort();
+    assert_eq!(s, sorted);
+}
+
+#[test] fn missing_glob_root() {
+    let s = SourceFileStrategy::Glob { include: vec!["missing/**/*.rs".to_owned()], exclude: Vec::new() }.resolve(&[], &WalkOptions::default());
+    match s {
+        Err(BuildError::Io { path, .. }) => assert_eq!(path, manifest_dir().join("missing")),
+        other => panic!("Expected an IO error, got {:?}", other.map(|r| r.files)),
+    }
+}
@@ -1,9 +1,17 @@
-use crate::{CompileStep, CompileSettings, CompileSettingsBuilder, PathType};
+use crate::{BuildError, CompileTool, Static, TryCompileStep, CompileSettings, CompileSettingsBuilder, Incremental, PathType};
+use crate::traits::mirror_intermediate_dir;
+use crate::compile_settings::SourceFile;
+use crate::incremental::{BuildDatabase, CompileRecord, settings_fingerprint};
+use crate::content_hash::{ContentHash, FileHashes};
+use crate::context::{CompileContext, Log};
+use crate::cache::ArtifactCache;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
-use std::ffi::OsString;
-use std::str::FromStr;
 use std::fs::create_dir_all;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 /**
 Implements a compile phase.  This compiles multiple sourcefiles into multiple object files.
@@ -11,39 +19,222 @@ Implements a compile phase.  This compiles multiple sourcefiles into multiple ob
 This is the first phase of a [super::build_system::BuildSystem].  Alternatively, it may
 be used on its own, for tasks that are one-file-per-product.
 */
-pub struct CompileSystem<Compiler: CompileStep> {
+pub struct CompileSystem<Compiler> {
     compiler: PhantomData<Compiler>
 }
 
-impl<Compiler: CompileStep> CompileSystem<Compiler> {
-    pub(crate) fn compile_all(settings: &CompileSettings) -> Vec<PathBuf> {
-        let source_files = settings.source_strategy.resolve::<Compiler>();
-        if source_files.is_empty() { panic!("Nothing to compile!") }
-        //todo: multithread this?
-        //todo: Incremental compiles?
+impl<Compiler: CompileTool> CompileSystem<Compiler> {
+    ///Returns a record for each sourcefile, in order.
+    pub(crate) fn compile_all(compiler: &Compiler, settings: &CompileSettings) -> Result<Vec<CompileRecord>, BuildError> {
+        let extensions = compiler.source_file_extensions();
+        let resolved = settings.source_strategy.resolve(&extensions, &settings.walk_options)?;
+        let source_files = resolved.files;
         //create intermediate path if it does not exist
-        create_dir_all(&settings.intermediate_path).unwrap();
-        create_dir_all(&settings.product_path).unwrap();
-        let mut dependency_path = settings.intermediate_path.clone();
-        dependency_path.push("dependency");
-        let mut compile_results = Vec::new();
-        for source_file in source_files {
-            let flags_iter = settings.flags.iter().map(|e| e as &str);
-            let result = Compiler::compile_one(&source_file,&settings.product_path,  &settings.configuration, &dependency_path,flags_iter);
-            compile_results.push(result);
+        create_dir(&settings.intermediate_path)?;
+        create_dir(&settings.product_path)?;
+        let cache_identity = compiler.cache_identity();
+        let database_path = BuildDatabase::path(&settings.intermediate_path, &cache_identity, &extensions);
+        //even without incremental builds, this tells us which sourcefiles went away
+        let previous_build = BuildDatabase::load(&database_path);
+        //each sourcefile gets its own dependency file, which we keep after the build
+        let dependency_paths = source_files.iter().map(|source| dependency_file(source, &settings.intermediate_path)).collect::<Result<Vec<_>, _>>()?;
+        check_collisions(dependency_paths.iter().zip(&source_files).map(|(d, s)| (d.as_path(), s.path.as_path())))?;
+        let settings_fingerprint = settings_fingerprint(&settings.flags, settings.configuration, compiler.tool_version().as_deref(), &cache_identity);
+        let file_hashes = FileHashes::default();
+        let log = Log::default();
+        let cache = settings.cache.as_ref().map(ArtifactCache::new);
+        let dependency_format = compiler.dependency_format();
 
-            super::dependency_parser::tell_cargo_about_dependencies(&dependency_path);
+        let jobs = settings.jobs.min(source_files.len());
+        //workers claim the next unclaimed source from this counter
+        let next_source = AtomicUsize::new(0);
+        //once anything fails, workers stop claiming sources, unless we keep going
+        let failed = AtomicBool::new(false);
+        //a panicking step stops everyone, even when keeping going, since the panic is resumed after the jobs finish
+        let panicked = AtomicBool::new(false);
+        //results are stored by source index, so output order matches input order regardless of which job finishes first
+        let compile_results = Mutex::new((0..source_files.len()).map(|_| None).collect::<Vec<Option<Result<CompileRecord, BuildError>>>>());
+        //new outputs are only shared through the cache once we know they don't collide
+        let cache_stores = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for job in 0..jobs {
+                let source_files = &source_files;
+                let dependency_paths = &dependency_paths;
+                let next_source = &next_source;
+                let failed = &failed;
+                let panicked = &panicked;
+                let compile_results = &compile_results;
+                let cache_stores = &cache_stores;
+                let previous_build = &previous_build;
+                let file_hashes = &file_hashes;
+                let log = &log;
+                let cache = &cache;
+                let cache_identity = &cache_identity;
+                scope.spawn(move || {
+                    let _guard = PanicGuard(panicked);
+                    while !panicked.load(Ordering::Relaxed) && (settings.keep_going || !failed.load(Ordering::Relaxed)) {
+                        let index = next_source.fetch_add(1, Ordering::Relaxed);
+                        let Some(source) = source_files.get(index) else { break };
+                        let source_file = &source.path;
+                        let result = match previous_build.records.get(source_file) {
+                            Some(record) if record.is_up_to_date(source_file, settings.incremental, settings_fingerprint, file_hashes) => Ok(record.clone()),
+                            _ => {
+                                //the first job runs on the token cargo already gave to the build script; other jobs need their own
+                                let _token = match jobserver_client() {
+                                    Some(client) if job > 0 => match client.acquire() {
+                                        Ok(token) => Some(token),
+                                        Err(error) => {
+                                            failed.store(true, Ordering::Relaxed);
+                                            compile_results.lock().unwrap()[index] = Some(Err(BuildError::Jobserver(error)));
+                                            continue
+                                        }
+                                    },
+                                    _ => None
+                                };
+                                let dependency_path = &dependency_paths[index];
+                                let cache_key = cache.as_ref().and_then(|_| ArtifactCache::key(cache_identity, settings_fingerprint, &settings.target, source_file, source.root.as_deref(), file_hashes));
+                                let restored = match (&cache, cache_key) {
+                                    (Some(cache), Some(key)) => cache.restore(key, source.root.as_deref(), &settings.product_path, file_hashes),
+                                    _ => None
+                                };
+                                match restored {
+                                    Some(cached) => {
+                                        let mut record = CompileRecord::new(cached.output, cached.dependencies);
+                                        record.output_hash = Some(cached.output_hash);
+                                        Ok(record)
+                                    }
+                                    None => {
+                                        //so we don't mistake the previous build's dependency file for this one's
+                                        let _ = std::fs::remove_file(dependency_path);
+                                        let context = CompileContext {
+                                            source: source_file,
+                                            source_root: source.root.as_deref(),
+                                            output_dir: &settings.product_path,
+                                            dependency_path,
+                                            configuration: settings.configuration,
+                                            flags: &settings.flags,
+                                            target: &settings.target,
+                                            log,
+                                            reported_dependencies: RefCell::new(None),
+                                        };
+                                        compiler.compile_one(&context).and_then(|output| {
+                                            let dependencies = match context.reported_dependencies.take() {
+                                                Some(reported) => super::dependency_parser::normalize(reported),
+                                                //without a dependency file, the sourcefile is the only dependency we know about
+                                                None => super::dependency_parser::read_dependencies(dependency_path, &output, dependency_format)?.unwrap_or_else(|| vec![source_file.clone()]),
+                                            };
+                                            let mut record = CompileRecord::new(output, dependencies);
+                                            if let Some(key) = cache_key {
+                                                cache_stores.lock().unwrap().push((index, key));
+                                            }
+                                            if settings.restat || settings.incremental == Incremental::ContentHash {
+                                                record.output_hash = ContentHash::of_file(&record.output);
+                                                let previous = previous_build.records.get(source_file);
+                                                if settings.restat && record.output_hash.is_some() && previous.is_some_and(|p| p.output == record.output && p.output_hash == record.output_hash) {
+                                                    log.info(&format!("{:?} compiled to the same output as before", source_file));
+                                                }
+                                            }
+                                            Ok(record)
+                                        }).map_err(|e| with_source(e, source_file))
+                                    }
+                                }.map(|mut record| {
+                                    record.settings = Some(settings_fingerprint);
+                                    record.dependency_file = Some(dependency_path.clone());
+                                    if settings.incremental == Incremental::ContentHash {
+                                        record.record_hashes(source_file, file_hashes);
+                                    }
+                                    record
+                                })
+                            }
+                        };
+                        if result.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        compile_results.lock().unwrap()[index] = Some(result);
+                    }
+                });
+            }
+        });
+        let current_sources: HashSet<PathBuf> = source_files.iter().map(|s| s.path.clone()).collect();
+        //cargo only remembers what the latest run of build.rs told it, so this includes files we skipped
+        let mut rerun_if_changed: BTreeSet<PathBuf> = resolved.watched.into_iter().collect();
+        let mut build = BuildDatabase::default();
+        let mut outputs = Vec::with_capacity(source_files.len());
+        let mut errors = Vec::new();
+        for (source_file, result) in source_files.iter().map(|s| s.path.clone()).zip(compile_results.into_inner().unwrap()) {
+            rerun_if_changed.insert(source_file.clone());
+            if let Some(Ok(record)) = &result {
+                rerun_if_changed.extend(record.dependencies.iter().cloned());
+            }
+            match result {
+                Some(Ok(record)) => {
+                    outputs.push(record.clone());
+                    build.records.insert(source_file, record);
+                }
+                Some(Err(error)) => errors.push(error),
+                //we stopped before reaching this file, so whatever we knew about it is still true
+                None => {
+                    if let Some(record) = previous_build.records.get(&source_file) {
+                        build.records.insert(source_file, record.clone());
+                    }
+                }
+            }
+        }
+        super::dependency_parser::tell_cargo_about_dependencies(&rerun_if_changed, &log);
+        let current_files: HashSet<&Path> = build.records.values().map(|r| r.output.as_path()).chain(dependency_paths.iter().map(PathBuf::as_path)).collect();
+        remove_orphans(&previous_build, &current_sources, &current_files, settings, &log);
+        //we only know outputs once they're compiled, so a collision has already overwritten one of them.
+        //at least don't remember or share it, so both sourcefiles compile again next time.
+        let collision = check_collisions(build.records.iter().map(|(source, record)| (record.output.as_path(), source.as_path())));
+        if let Err(BuildError::OutputCollision { first, second, .. }) = &collision {
+            build.records.remove(first);
+            build.records.remove(second);
+        }
+        build.save(&database_path)?;
+        if let Some(cache) = &cache {
+            for (index, key) in cache_stores.into_inner().unwrap() {
+                let source = &source_files[index];
+                let Some(record) = build.records.get(&source.path) else { continue };
+                //the build is still fine without the cache
+                if let Err(e) = cache.store(key, source.root.as_deref(), &settings.product_path, &record.output, &record.dependencies, &file_hashes) {
+                    log.warning(&format!("Problem storing {:?} in the cache: {}", record.output, e));
+                }
+            }
+            let stats = cache.finish()?;
+            log.info(&format!("buildkit cache: {} hits, {} misses", stats.hits, stats.misses));
+        }
+        collision?;
+        match errors.len() {
+            0 => Ok(outputs),
+            1 => Err(errors.pop().unwrap()),
+            _ if settings.keep_going => Err(BuildError::Multiple(errors)),
+            //other jobs may have failed before they noticed we were stopping; report the first sourcefile's error
+            _ => Err(errors.swap_remove(0))
         }
-        compile_results
     }
 }
 
-impl<Compiler: CompileStep> CompileSystem<Compiler> {
+impl<Compiler: CompileTool> CompileSystem<Compiler> {
+    ///Compiles with the compiler instance, using the settings specified.
+    ///
+    /// Returns paths to the compiled files.  Finding no sourcefiles isn't an error; outputs of removed sourcefiles are still cleaned up.
+    pub fn build_with(compiler: &Compiler, settings: &CompileSettings) -> Result<Vec<PathBuf>, BuildError> {
+        Self::compile_all(compiler, settings).map(outputs)
+    }
+
+    ///Build with the compiler instance, using no special settings.  See [CompileSystem::build_rs].
+    pub fn build_rs_with(compiler: &Compiler, exe_path: PathBuf) -> Result<Vec<PathBuf>, BuildError> {
+        let settings = CompileSettingsBuilder::new().intermediate_path(PathType::EXERelative(exe_path)).finish();
+        Self::build_with(compiler, &settings)
+    }
+}
+
+impl<Compiler: TryCompileStep> CompileSystem<Compiler> {
     ///Compiles using the settings specified.
     ///
     /// Returns a path to the final product.
-    pub fn build(settings: &CompileSettings) -> Vec<PathBuf> {
-        CompileSystem::<Compiler>::compile_all(&settings)
+    pub fn build(settings: &CompileSettings) -> Result<Vec<PathBuf>, BuildError> {
+        CompileSystem::compile_all(&Static::<Compiler>::new(), settings).map(outputs)
     }
 
     ///Build using no special settings.  Usually the entrypoint from `build.rs`
@@ -51,26 +242,558 @@ impl<Compiler: CompileStep> CompileSystem<Compiler> {
     /// `exe_path`: The path, relative to the built exe file, where the output should be located.
     /// For example if your target is built in `target\debug\my.exe` and `exe_path` is `assets\product.dll`, the final location will be
     /// `target\debug\assets\product.dll`.  The intermediate directories will be created if they do not already exist.
-    pub fn build_rs(exe_path: PathBuf) -> Vec<PathBuf> {
+    pub fn build_rs(exe_path: PathBuf) -> Result<Vec<PathBuf>, BuildError> {
         let settings = CompileSettingsBuilder::new().intermediate_path(PathType::EXERelative(exe_path)).finish();
         Self::build(&settings)
     }
 }
 
-///Walks a directory, looking for sourcefiles
+fn outputs(records: Vec<CompileRecord>) -> Vec<PathBuf> {
+    records.into_iter().map(|r| r.output).collect()
+}
+
+///Where `source` writes its dependency file.
+///
+/// This is named after the whole file name, e.g. `shader.frag.d`, so `shader.vert` and `shader.frag` don't share one.
+fn dependency_file(source: &SourceFile, intermediate_path: &Path) -> Result<PathBuf, BuildError> {
+    let dir = match &source.root {
+        Some(root) => mirror_intermediate_dir(&source.path, root, intermediate_path)?,
+        None => intermediate_path.to_path_buf(),
+    };
+    let mut file_name = source.path.file_name().unwrap().to_owned();
+    file_name.push(".d");
+    Ok(dir.join(file_name))
+}
+
+fn create_dir(path: &Path) -> Result<(), BuildError> {
+    create_dir_all(path).map_err(|error| BuildError::Io { path: path.to_path_buf(), error })
+}
+
+///Removes the outputs and dependency files of sourcefiles that were built last time but no longer exist.
 ///
-/// Returns its output in its argument, because it makes the memory
-/// faster for recursion
-pub fn dir_walk(base: &Path, extension: &str, output: &mut Vec<PathBuf>) {
-    for item in std::fs::read_dir(base).expect(&format!("Problem reading dir at {:?}",base)) {
-        let path = item.unwrap().path();
-        if path.is_dir() {
-            dir_walk(&path, extension, output);
-        }
-        else if path.is_file() { //I'm not 100% sure what other options there are, but ok
-            if path.extension() == Some(&OsString::from_str(extension).unwrap()) {
-                output.push(path);
+/// Only files in the intermediate or product directory are removed, since other files may not be ours.
+/// Files the current build uses are kept, since a moved sourcefile may reuse its old output.
+fn remove_orphans(previous_build: &BuildDatabase, current_sources: &HashSet<PathBuf>, current_files: &HashSet<&Path>, settings: &CompileSettings, log: &Log) {
+    for (source, record) in &previous_build.records {
+        if current_sources.contains(source) { continue }
+        //a sourcefile that still exists was only left out of this build, e.g. by a narrower glob
+        if source.exists() { continue }
+        for file in std::iter::once(&record.output).chain(&record.dependency_file) {
+            if current_files.contains(file.as_path()) { continue }
+            if !(file.starts_with(&settings.intermediate_path) || file.starts_with(&settings.product_path)) { continue }
+            match std::fs::remove_file(file) {
+                Ok(()) => log.info(&format!("Removed {:?}, since {:?} is gone", file, source)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => log.warning(&format!("Problem removing {:?}, left over from {:?}: {}", file, source, e)),
+            }
+        }
+    }
+}
+
+///Sets the flag if its job unwinds
+struct PanicGuard<'a>(&'a AtomicBool);
+
+impl Drop for PanicGuard<'_> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+///Fails if two sourcefiles share an output.  Takes `(output, source)` pairs.
+fn check_collisions<'a>(outputs: impl Iterator<Item=(&'a Path, &'a Path)>) -> Result<(), BuildError> {
+    let mut seen = HashMap::new();
+    for (output, source) in outputs {
+        if let Some(first) = seen.insert(output, source) {
+            let (first, second) = if first < source { (first, source) } else { (source, first) };
+            return Err(BuildError::OutputCollision { output: output.to_path_buf(), first: first.to_path_buf(), second: second.to_path_buf() })
+        }
+    }
+    Ok(())
+}
+
+///Fills in the sourcefile for compile errors that don't say which file failed
+fn with_source(error: BuildError, source_file: &Path) -> BuildError {
+    match error {
+        BuildError::Step(mut step) => {
+            if step.source.is_none() {
+                step.source = Some(source_file.to_path_buf());
             }
+            BuildError::Step(step)
         }
+        other => other
     }
-}
\ No newline at end of file
+}
+
+///The jobserver inherited from cargo (or make), if any.
+///
+/// This may only be read from the environment once per process, so we cache it.
+fn jobserver_client() -> Option<&'static jobserver::Client> {
+    static CLIENT: OnceLock<Option<jobserver::Client>> = OnceLock::new();
+    //safety: we only read the environment once, and don't otherwise touch the inherited file descriptors
+    CLIENT.get_or_init(|| unsafe { jobserver::Client::from_env() }).as_ref()
+}
+
+#[test] fn compile_order() {
+    use crate::{CompileStep, Configuration, SourceFileStrategy};
+    struct SlowCompiler;
+    impl CompileStep for SlowCompiler {
+        const SOURCE_FILE_EXTENSION: &'static str = "txt";
+        fn compile_one<'a>(path: &Path, intermediate_dir: &Path, _configuration: &crate::Configuration, dependency_path: &Path, _flags: impl Iterator<Item=&'a str>) -> PathBuf {
+            //finish the earlier files last
+            let n: u64 = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(50 - n * 10));
+            std::fs::write(dependency_path, format!("out: {}", path.display())).unwrap();
+            crate::suggest_intermediate_file(path, intermediate_dir.to_path_buf(), std::ffi::OsStr::new("o"))
+        }
+    }
+    let sources: Vec<PathBuf> = (0..5).map(|n| PathBuf::from(format!("{}.txt", n))).collect();
+    let mut intermediate = std::env::temp_dir();
+    intermediate.push("buildkit-compile-order");
+    let settings = CompileSettingsBuilder::new()
+        .source_strategy(SourceFileStrategy::SourceFiles(sources))
+        .intermediate_path(PathType::Exact(intermediate))
+        .configuration(Configuration::Debug)
+        .jobs(5)
+        .finish();
+    let results = CompileSystem::<SlowCompiler>::build(&settings).unwrap();
+    let stems: Vec<_> = results.iter().map(|r| r.file_stem().unwrap().to_str().unwrap().to_owned()).collect();
+    assert_eq!(stems, ["0", "1", "2", "3", "4"]);
+}
+
+#[test] fn incremental_timestamps() {
+    use crate::SourceFileStrategy;
+    use crate::test_support::{CopyCompiler, compile_settings, set_modified, test_dir, write_sources};
+    let dir = test_dir("incremental-timestamps");
+    let sources = write_sources(&dir, &["a.txt", "b.txt", "header.h"]);
+    let settings = compile_settings(&dir, SourceFileStrategy::SourceFiles(sources[..2].to_vec())).incremental(Incremental::Timestamps).finish();
+    let compiler = CopyCompiler { headers: vec!["header.h"], ..CopyCompiler::new() };
+    CompileSystem::build_with(&compiler, &settings).unwrap();
+    assert_eq!(compiler.compiles(), 2);
+    //each sourcefile keeps its own dependency file
+    assert!(std::fs::read_to_string(dir.join("out").join("a.txt.d")).unwrap().contains("a.txt"));
+    assert!(std::fs::read_to_string(dir.join("out").join("b.txt.d")).unwrap().contains("b.txt"));
+    CompileSystem::build_with(&compiler, &settings).unwrap();
+    assert_eq!(compiler.compiles(), 2);
+    //touching the source only recompiles that file
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+    set_modified(&sources[0], later);
+    CompileSystem::build_with(&compiler, &settings).unwrap();
+    assert_eq!(compiler.compiles(), 3);
+    //touching the shared header recompiles both
+    set_modified(&sources[2], later + std::time::Duration::from_secs(10));
+    CompileSystem::build_with(&compiler, &settings).unwrap();
+    assert_eq!(compiler.compiles(), 5);
+}
+
+#[test] fn incremental_content_hash() {
+    use crate::SourceFileStrategy;
+    use crate::test_support::{CopyCompiler, compile_settings, set_modified, test_dir, write_sources};
+    let dir = test_dir("incremental-content-hash");
+    let sources = write_sources(&dir, &["a.txt"]);
+    let mut builder = compile_settings(&dir, SourceFileStrategy::SourceFiles(sources.clone()));
+    builder.incremental(Incremental::ContentHash);
+    let compiler = CopyCompiler::new();
+    CompileSystem::build_with(&compiler, &builder.finish()).unwrap();
+    assert_eq!(compiler.compiles(), 1);
+    //a new modification time alone doesn't matter
+    set_modified(&sources[0], std::time::SystemTime::now() + std::time::Duration::from_secs(10));
+    CompileSystem::build_with(&compiler, &builder.finish()).unwrap();
+    assert_eq!(compiler.compiles(), 1);
+    //but new contents do
+    std::fs::write(&sources[0], "edited").unwrap();
+    CompileSystem::build_with(&compiler, &builder.finish()).unwrap();
+    assert_eq!(compiler.compiles(), 2);
+    //and so do new flags
+    builder.set_flags(vec!["-O".to_owned()]);
+    CompileSystem::build_with(&compiler, &builder.finish()).unwrap();
+    assert_eq!(compiler.compiles(), 3);
+}
+
+#[test] fn compile_failure() {
+    use crate::{Configuration, SourceFileStrategy, StepError, StepKind};
+    struct FailingCompiler;
+    impl TryCompileStep for FailingCompiler {
+        const SOURCE_FILE_EXTENSION: &'static str = "txt";
+        fn try_compile_one<'a>(_path: &Path, _intermediate_dir: &Path, _configuration: &crate::Configuration, _dependency_path: &Path, _flags: impl Iterator<Item=&'a str>) -> Result<PathBuf, BuildError> {
+            Err(StepError::new(StepKind::Compile, "syntax error").into())
+        }
+    }
+    let mut intermediate = std::env::temp_dir();
+    intermediate.push("buildkit-compile-failure");
+    let settings = CompileSettingsBuilder::new()
+        .source_strategy(SourceFileStrategy::SourceFiles(vec![PathBuf::from("bad.txt")]))
+        .intermediate_path(PathType::Exact(intermediate))
+        .configuration(Configuration::Debug)
+        .finish();
+    match CompileSystem::<FailingCompiler>::build(&settings) {
+        Err(BuildError::Step(step)) => {
+            assert_eq!(step.kind, StepKind::Compile);
+            assert_eq!(step.source, Some(PathBuf::from("bad.txt")));
+        }
+        other => panic!("Expected a step error, got {:?}", other)
+    }
+}
+
+#[test] fn keep_going() {
+    use crate::{Configuration, SourceFileStrategy, StepError, StepKind};
+    use std::sync::atomic::AtomicUsize;
+    static COMPILES: AtomicUsize = AtomicUsize::new(0);
+    struct FailingCompiler;
+    impl TryCompileStep for FailingCompiler {
+        const SOURCE_FILE_EXTENSION: &'static str = "txt";
+        fn try_compile_one<'a>(path: &Path, _intermediate_dir: &Path, _configuration: &crate::Configuration, _dependency_path: &Path, _flags: impl Iterator<Item=&'a str>) -> Result<PathBuf, BuildError> {
+            COMPILES.fetch_add(1, Ordering::Relaxed);
+            Err(StepError::new(StepKind::Compile, "syntax error").with_source(path).into())
+        }
+    }
+    let mut intermediate = std::env::temp_dir();
+    intermediate.push("buildkit-keep-going");
+    let sources: Vec<PathBuf> = (0..4).map(|n| PathBuf::from(format!("{}.txt", n))).collect();
+    let mut builder = CompileSettingsBuilder::new();
+    builder.source_strategy(SourceFileStrategy::SourceFiles(sources.clone()))
+        .intermediate_path(PathType::Exact(intermediate))
+        .configuration(Configuration::Debug)
+        .jobs(1);
+    //by default, we stop at the first failure
+    assert!(matches!(CompileSystem::<FailingCompiler>::build(&builder.finish()), Err(BuildError::Step(_))));
+    assert_eq!(COMPILES.load(Ordering::Relaxed), 1);
+    //even when several jobs fail at once
+    match CompileSystem::<FailingCompiler>::build(&builder.clone().jobs(4).finish()) {
+        Err(BuildError::Step(step)) => assert_eq!(step.source.as_ref(), Some(&sources[0])),
+        other => panic!("Expected the first failure, got {:?}", other)
+    }
+    COMPILES.store(1, Ordering::Relaxed);
+    match CompileSystem::<FailingCompiler>::build(&builder.keep_going(true).finish()) {
+        Err(BuildError::Multiple(errors)) => {
+            let failed: Vec<_> = errors.iter().map(|e| match e { BuildError::Step(step) => step.source.clone().unwrap(), other => panic!("{:?}", other) }).collect();
+            assert_eq!(failed, sources);
+        }
+        other => panic!("Expected every failure, got {:?}", other)
+    }
+    assert_eq!(COMPILES.load(Ordering::Relaxed), 5);
+}
+
+#[test] fn fingerprint_changes() {
+    use crate::{CompileStep, Configuration, SourceFileStrategy};
+    use crate::test_support::{compile_settings, test_dir, write_sources};
+    use std::sync::atomic::AtomicUsize;
+    static COMPILES: AtomicUsize = AtomicUsize::new(0);
+    static VERSION: AtomicUsize = AtomicUsize::new(1);
+    struct VersionedCompiler;
+    impl CompileStep for VersionedCompiler {
+        const SOURCE_FILE_EXTENSION: &'static str = "txt";
+        fn compile_one<'a>(path: &Path, intermediate_dir: &Path, _configuration: &crate::Configuration, _dependency_path: &Path, _flags: impl Iterator<Item=&'a str>) -> PathBuf {
+            COMPILES.fetch_add(1, Ordering::Relaxed);
+            let output = crate::suggest_intermediate_file(path, intermediate_dir.to_path_buf(), std::ffi::OsStr::new("out"));
+            std::fs::copy(path, &output).unwrap();
+            output
+        }
+        fn tool_version() -> Option<String> {
+            Some(format!("copy {}", VERSION.load(Ordering::Relaxed)))
+        }
+    }
+    let dir = test_dir("fingerprint-changes");
+    let mut builder = compile_settings(&dir, SourceFileStrategy::SourceFiles(write_sources(&dir, &["a.txt"])));
+    builder.incremental(Incremental::Timestamps);
+    CompileSystem::<VersionedCompiler>::build(&builder.finish()).unwrap();
+    CompileSystem::<VersionedCompiler>::build(&builder.finish()).unwrap();
+    assert_eq!(COMPILES.load(Ordering::Relaxed), 1);
+    builder.set_flags(vec!["-O".to_owned()]);
+    CompileSystem::<VersionedCompiler>::build(&builder.finish()).unwrap();
+    assert_eq!(COMPILES.load(Ordering::Relaxed), 2);
+    builder.configuration(Configuration::Release);
+    CompileSystem::<VersionedCompiler>::build(&builder.finish()).unwrap();
+    assert_eq!(COMPILES.load(Ordering::Relaxed), 3);
+    VERSION.store(2, Ordering::Relaxed);
+    CompileSystem::<VersionedCompiler>::build(&builder.finish()).unwrap();
+    assert_eq!(COMPILES.load(Ordering::Relaxed), 4);
+    CompileSystem::<VersionedCompiler>::build(&builder.finish()).unwrap();
+    assert_eq!(COMPILES.load(Ordering::Relaxed), 4);
+}
+
+#[test] fn mirrored_outputs() {
+    use crate::{CompileStep, SourceFileStrategy};
+    use crate::test_support::{CopyCompiler, compile_settings, test_dir, write_sources};
+    ///Static steps get a single intermediate directory
+    struct StaticCompiler;
+    impl CompileStep for StaticCompiler {
+        const SOURCE_FILE_EXTENSION: &'static str = "txt";
+        fn compile_one<'a>(path: &Path, intermediate_dir: &Path, _configuration: &crate::Configuration, _dependency_path: &Path, _flags: impl Iterator<Item=&'a str>) -> PathBuf {
+            crate::suggest_intermediate_file(path, intermediate_dir.to_path_buf(), std::ffi::OsStr::new("out"))
+        }
+    }
+    ///Unless they ask for a mirrored one
+    struct MirroringCompiler;
+    impl CompileStep for MirroringCompiler {
+        const SOURCE_FILE_EXTENSION: &'static str = "txt";
+        const MIRROR_INTERMEDIATE_DIR: bool = true;
+        fn compile_one<'a>(path: &Path, intermediate_dir: &Path, _configuration: &crate::Configuration, _dependency_path: &Path, _flags: impl Iterator<Item=&'a str>) -> PathBuf {
+            crate::suggest_intermediate_file(path, intermediate_dir.to_path_buf(), std::ffi::OsStr::new("out"))
+        }
+    }
+    let dir = test_dir("mirrored-outputs");
+    write_sources(&dir, &["src/ui/button.txt", "src/world/button.txt"]);
+    let settings = compile_settings(&dir, SourceFileStrategy::SearchFromManifest(vec![dir.join("src")])).finish();
+    let mut outputs = CompileSystem::build_with(&CopyCompiler::new(), &settings).unwrap();
+    outputs.sort();
+    assert_eq!(outputs, [dir.join("out").join("ui").join("button.out"), dir.join("out").join("world").join("button.out")]);
+    assert_eq!(std::fs::read_to_string(&outputs[1]).unwrap(), "src/world/button.txt");
+    let mut outputs = CompileSystem::<MirroringCompiler>::build(&settings).unwrap();
+    outputs.sort();
+    assert_eq!(outputs, [dir.join("out").join("ui").join("button.out"), dir.join("out").join("world").join("button.out")]);
+    match CompileSystem::<StaticCompiler>::build(&settings) {
+        Err(BuildError::OutputCollision { output, first, second }) => {
+            assert_eq!(output, dir.join("out").join("button.out"));
+            assert_eq!(first, dir.join("src").join("ui").join("button.txt"));
+            assert_eq!(second, dir.join("src").join("world").join("button.txt"));
+            //the overwritten output isn't trusted next time
+            let database = BuildDatabase::load(&BuildDatabase::path(&dir.join("out"), &crate::Static::<StaticCompiler>::new().cache_identity(), &["txt"]));
+            assert!(!database.records.contains_key(&first) && !database.records.contains_key(&second));
+        }
+        other => panic!("Expected a collision, got {:?}", other)
+    }
+}
+
+#[test] fn relative_sources() {
+    use crate::{CompileContext, SourceFileStrategy};
+    use crate::test_support::{compile_settings, test_dir};
+    ///The sourcefiles don't exist, so just write empty outputs
+    struct TouchCompiler;
+    impl CompileTool for TouchCompiler {
+        fn source_file_extension(&self) -> &str { "vert" }
+        fn compile_one(&self, context: &CompileContext) -> Result<PathBuf, BuildError> {
+            let output = context.intermediate_file("spv")?;
+            std::fs::write(&output, "").unwrap();
+            std::fs::write(context.dependency_path, format!("{}: {}", output.display(), context.source.display())).unwrap();
+            Ok(output)
+        }
+    }
+    let dir = test_dir("relative-sources");
+    let settings = compile_settings(&dir, SourceFileStrategy::SourceFiles(vec![PathBuf::from("ui/b.vert"), PathBuf::from("world/b.vert")])).finish();
+    let outputs = CompileSystem::build_with(&TouchCompiler, &settings).unwrap();
+    let out = dir.join("out");
+    assert_eq!(outputs, [out.join("ui").join("b.spv"), out.join("world").join("b.spv")]);
+    assert!(out.join("ui").join("b.vert.d").exists());
+    assert!(out.join("world").join("b.vert.d").exists());
+}
+
+#[test] fn no_sources() {
+    use crate::SourceFileStrategy;
+    use crate::test_support::{CopyCompiler, compile_settings, test_dir, write_sources};
+    let dir = test_dir("no-sources");
+    let sources = write_sources(&dir, &["src/a.txt"]);
+    let settings = compile_settings(&dir, SourceFileStrategy::SearchFromManifest(vec![dir.join("src")])).finish();
+    CompileSystem::build_with(&CopyCompiler::new(), &settings).unwrap();
+    //removing the last sourcefile is an empty build, which still cleans up
+    std::fs::remove_file(&sources[0]).unwrap();
+    assert!(CompileSystem::build_with(&CopyCompiler::new(), &settings).unwrap().is_empty());
+    assert!(!dir.join("out").join("a.out").exists());
+}
+
+#[test] fn shared_stems() {
+    use crate::{CompileContext, SourceFileStrategy};
+    use crate::test_support::{compile_settings, test_dir, write_sources};
+    ///Names outputs after the whole file name
+    struct ShaderCompiler;
+    impl CompileTool for ShaderCompiler {
+        fn source_file_extension(&self) -> &str { "vert" }
+        fn source_file_extensions(&self) -> Vec<&str> { vec!["vert", "frag"] }
+        fn compile_one(&self, context: &CompileContext) -> Result<PathBuf, BuildError> {
+            let mut name = context.source.file_name().unwrap().to_owned();
+            name.push(".spv");
+            let output = context.output_dir.join(name);
+            std::fs::copy(context.source, &output).unwrap();
+            std::fs::write(context.dependency_path, format!("{}: {}", output.display(), context.source.display())).unwrap();
+            Ok(output)
+        }
+    }
+    let dir = test_dir("shared-stems");
+    let sources = write_sources(&dir, &["shader.frag", "shader.vert"]);
+    let settings = compile_settings(&dir, SourceFileStrategy::SourceFiles(sources)).finish();
+    assert_eq!(CompileSystem::build_with(&ShaderCompiler, &settings).unwrap().len(), 2);
+    assert!(std::fs::read_to_string(dir.join("out").join("shader.frag.d")).unwrap().contains("shader.frag"));
+    assert!(std::fs::read_to_string(dir.join("out").join("shader.vert.d")).unwrap().contains("shader.vert"));
+}
+
+#[test] fn artifact_cache() {
+    use crate::{CacheStats, SourceFileStrategy};
+    use crate::test_support::{CopyCompiler, compile_settings, test_dir, write_sources};
+    let dir = test_dir("artifact-cache");
+    let cache_dir = dir.join("cache");
+    //two checkouts of the same sources
+    let settings = |worktree: &str, header: &str, max_size: u64| {
+        let src = dir.join(worktree).join("src");
+        write_sources(&src, &["ui/a.txt"]);
+        std::fs::write(src.join("ui").join("header.h"), header).unwrap();
+        compile_settings(&dir.join(worktree), SourceFileStrategy::SearchFromManifest(vec![src]))
+            .cache_dir(cache_dir.clone())
+            .cache_max_size(max_size)
+            .finish()
+    };
+    let compiler = CopyCompiler { headers: vec!["header.h"], ..CopyCompiler::new() };
+    CompileSystem::build_with(&compiler, &settings("one", "h", 1 << 20)).unwrap();
+    assert_eq!(compiler.compiles(), 1);
+    let outputs = CompileSystem::build_with(&compiler, &settings("two", "h", 1 << 20)).unwrap();
+    assert_eq!(compiler.compiles(), 1);
+    assert_eq!(outputs, [dir.join("two").join("out").join("ui").join("a.out")]);
+    assert_eq!(std::fs::read_to_string(&outputs[0]).unwrap(), "ui/a.txt");
+    assert_eq!(CacheStats::load(&cache_dir), CacheStats { hits: 1, misses: 1 });
+    //a changed dependency misses
+    CompileSystem::build_with(&compiler, &settings("two", "changed", 1 << 20)).unwrap();
+    assert_eq!(compiler.compiles(), 2);
+    //a cache too small for anything is emptied
+    CompileSystem::build_with(&compiler, &settings("three", "new", 0)).unwrap();
+    assert_eq!(compiler.compiles(), 3);
+    assert_eq!(std::fs::read_dir(cache_dir.join("objects")).unwrap().count(), 0);
+}
+
+#[test] fn removed_sources() {
+    use crate::SourceFileStrategy;
+    use crate::test_support::{CopyCompiler, compile_settings, test_dir, write_sources};
+    let dir = test_dir("removed-sources");
+    let sources = write_sources(&dir, &["src/a.txt", "src/b.txt"]);
+    let settings = compile_settings(&dir, SourceFileStrategy::SearchFromManifest(vec![dir.join("src")])).finish();
+    assert_eq!(CompileSystem::build_with(&CopyCompiler::new(), &settings).unwrap().len(), 2);
+    assert!(dir.join("out").join("b.txt.d").exists());
+    std::fs::remove_file(&sources[1]).unwrap();
+    assert_eq!(CompileSystem::build_with(&CopyCompiler::new(), &settings).unwrap(), [dir.join("out").join("a.out")]);
+    assert!(!dir.join("out").join("b.out").exists());
+    assert!(!dir.join("out").join("b.txt.d").exists());
+}
+
+#[test] fn moved_sources() {
+    use crate::{CompileContext, SourceFileStrategy};
+    use crate::test_support::{compile_settings, test_dir, write_sources};
+    ///Names outputs by file name alone, so a moved sourcefile keeps its output
+    struct FlatCompiler;
+    impl CompileTool for FlatCompiler {
+        fn source_file_extension(&self) -> &str { "txt" }
+        fn compile_one(&self, context: &CompileContext) -> Result<PathBuf, BuildError> {
+            let output = crate::suggest_intermediate_file(context.source, context.output_dir.to_path_buf(), std::ffi::OsStr::new("out"));
+            std::fs::copy(context.source, &output).unwrap();
+            Ok(output)
+        }
+    }
+    let dir = test_dir("moved-sources");
+    let sources = write_sources(&dir, &["src/old/x.txt"]);
+    create_dir_all(dir.join("src").join("new")).unwrap();
+    let settings = compile_settings(&dir, SourceFileStrategy::SearchFromManifest(vec![dir.join("src")])).finish();
+    CompileSystem::build_with(&FlatCompiler, &settings).unwrap();
+    std::fs::rename(&sources[0], dir.join("src").join("new").join("x.txt")).unwrap();
+    let outputs = CompileSystem::build_with(&FlatCompiler, &settings).unwrap();
+    assert_eq!(outputs, [dir.join("out").join("x.out")]);
+    assert!(outputs[0].exists());
+}
+
+#[test] fn optional_dependencies() {
+    use crate::SourceFileStrategy;
+    use crate::test_support::{compile_settings, set_modified, test_dir, write_sources};
+    use std::sync::atomic::AtomicUsize;
+    static COMPILES: AtomicUsize = AtomicUsize::new(0);
+    ///Writes a dependency file only if the source names a header
+    struct IncludeCompiler;
+    impl TryCompileStep for IncludeCompiler {
+        const SOURCE_FILE_EXTENSION: &'static str = "txt";
+        fn try_compile_one<'a>(path: &Path, intermediate_dir: &Path, _configuration: &crate::Configuration, dependency_path: &Path, _flags: impl Iterator<Item=&'a str>) -> Result<PathBuf, BuildError> {
+            COMPILES.fetch_add(1, Ordering::Relaxed);
+            let output = crate::suggest_intermediate_file(path, intermediate_dir.to_path_buf(), std::ffi::OsStr::new("out"));
+            let header = std::fs::read_to_string(path).unwrap();
+            if !header.is_empty() {
+                std::fs::write(dependency_path, format!("{}: {} {}", output.display(), path.display(), path.with_file_name(header).display())).unwrap();
+            }
+            std::fs::write(&output, "").unwrap();
+            Ok(output)
+        }
+    }
+    let dir = test_dir("optional-dependencies");
+    let sources = write_sources(&dir, &["a.txt", "header.h"]);
+    let source = sources[0].clone();
+    std::fs::write(&source, "header.h").unwrap();
+    let settings = compile_settings(&dir, SourceFileStrategy::SourceFiles(vec![source.clone()])).incremental(Incremental::Timestamps).finish();
+    CompileSystem::<IncludeCompiler>::build(&settings).unwrap();
+    //a deleted header means compiling again, not failing, even though the sourcefile looks older than its output
+    std::fs::remove_file(&sources[1]).unwrap();
+    std::fs::write(&source, "").unwrap();
+    set_modified(&source, std::time::SystemTime::now() - std::time::Duration::from_secs(3600));
+    CompileSystem::<IncludeCompiler>::build(&settings).unwrap();
+    assert_eq!(COMPILES.load(Ordering::Relaxed), 2);
+    //now there's no dependency file, so only the sourcefile is tracked
+    let database = BuildDatabase::load(&BuildDatabase::path(&dir.join("out"), &crate::Static::<IncludeCompiler>::new().cache_identity(), &["txt"]));
+    assert_eq!(database.records[&source].dependencies, [source]);
+}
+
+#[test] fn shared_intermediate_dir() {
+    use crate::SourceFileStrategy;
+    use crate::test_support::{CopyCompiler, compile_settings, test_dir, write_sources};
+    let dir = test_dir("shared-intermediate-dir");
+    write_sources(&dir, &["src/a.vert", "src/b.frag"]);
+    let settings = compile_settings(&dir, SourceFileStrategy::SearchFromManifest(vec![dir.join("src")])).incremental(Incremental::Timestamps).finish();
+    let vertex = CopyCompiler { extension: "vert", ..CopyCompiler::new() };
+    let fragment = CopyCompiler { extension: "frag", ..CopyCompiler::new() };
+    let vertex_outputs = CompileSystem::build_with(&vertex, &settings).unwrap();
+    CompileSystem::build_with(&fragment, &settings).unwrap();
+    //each step keeps its own database, so neither removes the other's outputs
+    assert!(vertex_outputs[0].exists());
+    CompileSystem::build_with(&vertex, &settings).unwrap();
+    assert_eq!(vertex.compiles(), 1);
+    assert!(dir.join("out").join("b.out").exists());
+}
+
+#[test] fn excluded_sources() {
+    use crate::SourceFileStrategy;
+    use crate::test_support::{CopyCompiler, compile_settings, test_dir, write_sources};
+    let dir = test_dir("excluded-sources");
+    let sources = write_sources(&dir, &["a.txt", "b.txt"]);
+    let compiler = CopyCompiler::new();
+    CompileSystem::build_with(&compiler, &compile_settings(&dir, SourceFileStrategy::SourceFiles(sources.clone())).finish()).unwrap();
+    //a sourcefile that is only left out of the build keeps its output
+    CompileSystem::build_with(&compiler, &compile_settings(&dir, SourceFileStrategy::SourceFiles(sources[..1].to_vec())).finish()).unwrap();
+    assert!(dir.join("out").join("b.out").exists());
+}
+
+#[test] fn panicking_step() {
+    use crate::{CompileContext, SourceFileStrategy};
+    use crate::test_support::{compile_settings, test_dir};
+    use std::sync::atomic::AtomicUsize;
+    ///Crashes on the first sourcefile
+    struct PanicCompiler { compiles: AtomicUsize }
+    impl CompileTool for PanicCompiler {
+        fn source_file_extension(&self) -> &str { "txt" }
+        fn compile_one(&self, context: &CompileContext) -> Result<PathBuf, BuildError> {
+            self.compiles.fetch_add(1, Ordering::Relaxed);
+            if context.source.ends_with("0.txt") { panic!("compiler crashed") }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            Ok(context.output_dir.join(context.source))
+        }
+    }
+    let dir = test_dir("panicking-step");
+    let sources = (0..20).map(|i| PathBuf::from(format!("{}.txt", i))).collect();
+    let settings = compile_settings(&dir, SourceFileStrategy::SourceFiles(sources)).jobs(2).finish();
+    let compiler = PanicCompiler { compiles: AtomicUsize::new(0) };
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| CompileSystem::build_with(&compiler, &settings))).is_err());
+    //the other job stops claiming sourcefiles
+    assert!(compiler.compiles.load(Ordering::Relaxed) < 5);
+}
+
+#[test] fn identity_changes() {
+    use crate::{CompileContext, SourceFileStrategy};
+    use crate::test_support::{CopyCompiler, compile_settings, test_dir, write_sources};
+    ///A copy compiler with an include directory
+    struct IncludeCompiler { include_dir: &'static str, copy: CopyCompiler }
+    impl CompileTool for IncludeCompiler {
+        fn source_file_extension(&self) -> &str { "txt" }
+        fn compile_one(&self, context: &CompileContext) -> Result<PathBuf, BuildError> { self.copy.compile_one(context) }
+        fn cache_identity(&self) -> String { format!("include {}", self.include_dir) }
+    }
+    let dir = test_dir("identity-changes");
+    let settings = compile_settings(&dir, SourceFileStrategy::SourceFiles(write_sources(&dir, &["a.txt"]))).incremental(Incremental::Timestamps).finish();
+    let mut compiler = IncludeCompiler { include_dir: "one", copy: CopyCompiler::new() };
+    CompileSystem::build_with(&compiler, &settings).unwrap();
+    CompileSystem::build_with(&compiler, &settings).unwrap();
+    assert_eq!(compiler.copy.compiles(), 1);
+    compiler.include_dir = "two";
+    CompileSystem::build_with(&compiler, &settings).unwrap();
+    assert_eq!(compiler.copy.compiles(), 2);
+}
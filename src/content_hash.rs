@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+///A hash of some content that is stable across builds, machines and Rust versions.
+///
+/// This is 128-bit FNV-1a.  It is not cryptographic, but it only needs to notice edits.
+#[derive(Copy,Clone,Debug,PartialEq,Eq,Hash)]
+pub(crate) struct ContentHash(u128);
+
+const OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+const PRIME: u128 = 0x0000000001000000000000000000013B;
+
+///Incrementally computes a [ContentHash].
+pub(crate) struct ContentHasher(u128);
+
+impl ContentHasher {
+    pub(crate) fn new() -> Self { ContentHasher(OFFSET_BASIS) }
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u128;
+            self.0 = self.0.wrapping_mul(PRIME);
+        }
+    }
+    ///Writes a field followed by a separator, so that `["ab","c"]` and `["a","bc"]` hash differently.
+    pub(crate) fn write_field(&mut self, bytes: &[u8]) {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+    pub(crate) fn finish(&self) -> ContentHash { ContentHash(self.0) }
+}
+
+impl ContentHash {
+    pub(crate) fn of_file(path: &Path) -> Option<ContentHash> {
+        let contents = std::fs::read(path).ok()?;
+        let mut hasher = ContentHasher::new();
+        hasher.write(&contents);
+        Some(hasher.finish())
+    }
+    pub(crate) fn parse(hex: &str) -> Option<ContentHash> {
+        if hex.len() != 32 { return None }
+        u128::from_str_radix(hex, 16).ok().map(ContentHash)
+    }
+}
+
+impl Display for ContentHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+///Hashes files at most once per build, since many sourcefiles tend to share the same headers.
+#[derive(Default)]
+pub(crate) struct FileHashes {
+    hashes: Mutex<HashMap<PathBuf, Option<ContentHash>>>,
+}
+
+impl FileHashes {
+    ///Hash of the file's contents, or `None` if it can't be read.
+    pub(crate) fn get(&self, path: &Path) -> Option<ContentHash> {
+        if let Some(hash) = self.hashes.lock().unwrap().get(path) {
+            return *hash;
+        }
+        //don't hold the lock while reading the file
+        let hash = ContentHash::of_file(path);
+        self.hashes.lock().unwrap().insert(path.to_path_buf(), hash);
+        hash
+    }
+}
+
+#[test] fn fnv_vectors() {
+    let hash = |s: &str| { let mut h = ContentHasher::new(); h.write(s.as_bytes()); h.finish().to_string() };
+    assert_eq!(hash(""), "6c62272e07bb014262b821756295c58d");
+    assert_eq!(hash("a"), "d228cb696f1a8caf78912b704e4a8964");
+    assert_eq!(ContentHash::parse(&hash("a")).unwrap().to_string(), hash("a"));
+}
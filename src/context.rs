@@ -0,0 +1,108 @@
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::{BuildError, Configuration, mirror_intermediate_file, suggest_intermediate_file};
+
+///Describes the platform being built for.
+#[derive(Clone,Debug,Default,PartialEq,Eq)]
+#[non_exhaustive]
+pub struct TargetInfo {
+    ///The target triple, e.g. `aarch64-apple-darwin`.  From cargo's `TARGET` environment variable.
+    pub triple: Option<String>,
+    ///The triple of the machine running the build.  From cargo's `HOST` environment variable.
+    pub host: Option<String>,
+}
+
+impl TargetInfo {
+    ///Reads the target from the environment cargo provides to `build.rs`.
+    pub fn from_env() -> Self {
+        TargetInfo {
+            triple: std::env::var("TARGET").ok(),
+            host: std::env::var("HOST").ok(),
+        }
+    }
+}
+
+///Reports messages from a compile or link step.
+#[derive(Debug,Default)]
+pub struct Log {
+    _private: (),
+}
+
+impl Log {
+    ///Shows a warning in cargo's output.
+    pub fn warning(&self, message: &str) {
+        //cargo only reads one line per directive
+        for line in message.lines() {
+            println!("cargo:warning={}", line);
+        }
+    }
+    ///Records a message in the build script's output, which cargo shows when the build fails or with `-vv`.
+    pub fn info(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+}
+
+///Everything a [crate::CompileTool] needs to compile one sourcefile.
+///
+/// Buildkit may add fields to this in the future.
+#[non_exhaustive]
+pub struct CompileContext<'a> {
+    ///Path to the source file
+    pub source: &'a Path,
+    ///The search path or glob root where `source` was found, if any.  See [crate::mirror_intermediate_file].
+    ///
+    /// For a relative path in [crate::SourceFileStrategy::SourceFiles], this is empty.
+    pub source_root: Option<&'a Path>,
+    ///Output location for object files.  To get a path for storing your object file, consider calling [CompileContext::intermediate_file].
+    pub output_dir: &'a Path,
+    ///Output file containing discovered dependencies, unique to this sourcefile, in the [crate::CompileTool::dependency_format].
+    /// See [crate::CompileStep::compile_one].
+    pub dependency_path: &'a Path,
+    pub configuration: Configuration,
+    ///Compiler flags
+    pub flags: &'a [String],
+    pub target: &'a TargetInfo,
+    pub log: &'a Log,
+    ///Set by [CompileContext::report_dependencies]
+    pub(crate) reported_dependencies: RefCell<Option<Vec<PathBuf>>>,
+}
+
+impl CompileContext<'_> {
+    ///Flags as `&str`, as [crate::CompileStep::compile_one] expects them.
+    pub fn flags_iter(&self) -> impl Iterator<Item=&str> {
+        self.flags.iter().map(|f| f as &str)
+    }
+    ///Tells buildkit which files the compile read, as an alternative to writing [CompileContext::dependency_path].
+    ///
+    /// Calling this more than once adds to the list.
+    pub fn report_dependencies(&self, dependencies: impl IntoIterator<Item=PathBuf>) {
+        self.reported_dependencies.borrow_mut().get_or_insert_with(Vec::new).extend(dependencies);
+    }
+    ///A path in [CompileContext::output_dir] for the object file, mirroring where the source is under [CompileContext::source_root].
+    ///
+    /// `extension` is the extension for object files, e.g. `o`.
+    pub fn intermediate_file(&self, extension: &str) -> Result<PathBuf, BuildError> {
+        match self.source_root {
+            Some(root) => mirror_intermediate_file(self.source, root, self.output_dir, OsStr::new(extension)),
+            None => Ok(suggest_intermediate_file(self.source, self.output_dir.to_path_buf(), OsStr::new(extension))),
+        }
+    }
+}
+
+///Everything a [crate::LinkTool] needs to link the compiled files.
+///
+/// Buildkit may add fields to this in the future.
+#[non_exhaustive]
+pub struct LinkContext<'a> {
+    ///The compiled files, in the same order as the sourcefiles
+    pub object_files: &'a [PathBuf],
+    ///Where to put the product
+    pub output_dir: &'a Path,
+    ///The product name, e.g. libname or similar
+    pub product_name: &'a str,
+    pub configuration: Configuration,
+    pub target: &'a TargetInfo,
+    pub log: &'a Log,
+}
@@ -1,78 +1,403 @@
-use std::io::Read;
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
 use std::path::{Path,PathBuf};
-use std::str::FromStr;
-
-pub fn tell_cargo_about_dependencies(dependency_file: &Path) {
-    let mut file = std::fs::File::open(dependency_file).unwrap();
-    let mut str = String::new();
-    let _ = file.read_to_string(&mut str).unwrap();
-    for dependency in parse(&str) {
-        println!("cargo:rerun-if-changed='{}'",dependency.to_str().unwrap());
+
+use crate::{BuildError, Log};
+
+///How a compile step writes its dependency file.
+///
+/// Whatever the format, buildkit ends up with the same list of paths, which it uses for incremental builds and to tell cargo when to rerun.
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+#[non_exhaustive]
+pub enum DependencyFormat {
+    ///Makefile rules, as written by `-MD -MF` in GCC, Clang, glslc and dxc.  See [parse_depfile].
+    #[default]
+    Makefile,
+    ///The output of MSVC's `/showIncludes`.  Lines starting with `Note: including file:` name a dependency; other lines are ignored.
+    MsvcShowIncludes,
+    ///A Ninja deps log (`.ninja_deps`), as written by `ninja -t recompact` or tools that emit Ninja's binary format.
+    ///
+    /// We use the inputs of the compiled output.  If the log names the output differently, it must contain only one output.
+    NinjaDeps,
+    ///A JSON array of path strings, e.g. `["src/a.frag", "src/common.h"]`.
+    Json,
+    ///One path per line, without escaping.
+    PlainList,
+}
+
+///Reads the dependencies listed in a dependency file
+///
+/// Returns each path once, in the order it first appears, or `None` if the step didn't write the file.
+/// `output` is the file the step compiled, which picks a record from a [DependencyFormat::NinjaDeps] log.
+pub fn read_dependencies(dependency_file: &Path, output: &Path, format: DependencyFormat) -> Result<Option<Vec<PathBuf>>, BuildError> {
+    if !dependency_file.exists() { return Ok(None) }
+    let io_error = |error| BuildError::Io { path: dependency_file.to_path_buf(), error };
+    let parse_error = |error| BuildError::Depfile { path: dependency_file.to_path_buf(), error };
+    let read_text = || std::fs::read_to_string(dependency_file).map_err(io_error);
+    let dependencies = match format {
+        DependencyFormat::Makefile => parse_depfile(&read_text()?).map_err(parse_error)?.into_iter().flat_map(|r| r.prerequisites).collect(),
+        DependencyFormat::MsvcShowIncludes => parse_show_includes(&read_text()?),
+        DependencyFormat::NinjaDeps => parse_ninja_deps(&std::fs::read(dependency_file).map_err(io_error)?, output).map_err(parse_error)?,
+        DependencyFormat::Json => parse_json_list(&read_text()?).map_err(parse_error)?,
+        DependencyFormat::PlainList => read_text()?.lines().map(|l| l.trim_end_matches('\r')).filter(|l| !l.is_empty()).map(PathBuf::from).collect(),
+    };
+    Ok(Some(normalize(dependencies)))
+}
+
+///Removes duplicates, keeping the first of each
+pub(crate) fn normalize(dependencies: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut out: Vec<PathBuf> = Vec::with_capacity(dependencies.len());
+    for dependency in dependencies {
+        if !out.contains(&dependency) {
+            out.push(dependency);
+        }
     }
+    out
 }
 
-// Accepts input like `whatever: file.metal file.h` supporting newlines and escapes
-fn parse(parse_me: &str) -> Vec<PathBuf> {
-    let mut iter = parse_me.chars();
-    //first we advance until we find a `:`
-    let _ = iter.by_ref().take_while(|p| p != &':').for_each(drop);
-    //drop whitespace
-    let _ = iter.by_ref().take_while(|p| p != &' ').for_each(drop);
+///Tells cargo to rerun the build script when any of these files change.
+///
+/// Paths cargo can't read back, because they aren't UTF-8 or contain a newline, are reported as warnings instead.
+pub fn tell_cargo_about_dependencies(dependencies: &BTreeSet<PathBuf>, log: &Log) {
+    for line in rerun_if_changed_lines(dependencies) {
+        match line {
+            Ok(line) => println!("{}", line),
+            Err(path) => log.warning(&format!("Can't tell cargo to watch {:?}, so changing it won't rebuild", path)),
+        }
+    }
+}
 
-    let mut out = Vec::new();
-    //if we immediately parsed `\` in the previoius iteration
-    let mut escaped = false;
-    let mut current_str = String::new();
-    for char in iter {
-        //println!("match {}",char);
-        match char {
-            ' ' => {
-                if current_str.len() == 0 {
-                    //waiting for next string to start
-                }
-                else if escaped {
-                    current_str.push(char);
-                    escaped = false;
+fn rerun_if_changed_lines(dependencies: &BTreeSet<PathBuf>) -> impl Iterator<Item=Result<String, &Path>> {
+    dependencies.iter().map(|dependency| match dependency.to_str() {
+        Some(path) if !path.contains(['\n', '\r']) => Ok(format!("cargo:rerun-if-changed={}", path)),
+        _ => Err(dependency.as_path()),
+    })
+}
+
+///One rule from a Makefile-style dependency file, e.g. `file.o: file.c file.h`
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct DepfileRule {
+    pub targets: Vec<PathBuf>,
+    pub prerequisites: Vec<PathBuf>,
+}
+
+///A dependency file we couldn't parse
+#[derive(Clone,Debug,PartialEq,Eq)]
+#[non_exhaustive]
+pub struct DepfileError {
+    ///Line number, starting from 1.  For [DependencyFormat::NinjaDeps], this is the record number instead.
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for DepfileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for DepfileError {}
+
+/**
+Parses a Makefile-style dependency file, as written by GCC, Clang, glslc, dxc and similar tools with `-MD` or `-MF`.
+
+Supports several rules per file (including the empty rules `-MP` writes for each header), several targets per rule,
+`\` line continuations, tabs or spaces between paths, `#` comments, and the escapes `\ ` (space), `\#`, `\\` and `$$`.
+Other backslashes are kept, so Windows paths like `C:\foo.o: C:\foo.c` work.  A `:` only separates targets from
+prerequisites when followed by whitespace, so drive letters don't.
+*/
+pub fn parse_depfile(contents: &str) -> Result<Vec<DepfileRule>, DepfileError> {
+    let chars: Vec<char> = contents.chars().collect();
+    let mut rules = Vec::new();
+    let mut targets = Vec::new();
+    let mut prerequisites = Vec::new();
+    //whether we are past the `:` of the current rule
+    let mut seen_colon = false;
+    let mut word = String::new();
+    let mut line = 1;
+    let mut i = 0;
+    let error = |line, message: &str| DepfileError { line, message: message.to_owned() };
+    loop {
+        let c = chars.get(i).copied();
+        let next = chars.get(i + 1).copied();
+        i += 1;
+        let ends_word = match c {
+            None | Some('\n' | ' ' | '\t' | '\r') => true,
+            Some('\\') => match next {
+                //continues the rule on the next line
+                Some('\n') => { i += 1; line += 1; true }
+                Some('\r') if chars.get(i + 1) == Some(&'\n') => { i += 2; line += 1; true }
+                Some(escaped @ (' ' | '#' | '\\')) => { word.push(escaped); i += 1; false }
+                _ => { word.push('\\'); false }
+            }
+            Some('$') if next == Some('$') => { word.push('$'); i += 1; false }
+            Some('#') => {
+                //comment until the end of the line
+                while chars.get(i).is_some_and(|c| *c != '\n') { i += 1; }
+                true
+            }
+            Some(':') if next.is_none_or(|n| n.is_whitespace()) || (next == Some('\\') && matches!(chars.get(i + 1), Some('\n' | '\r'))) => true,
+            Some(other) => { word.push(other); false }
+        };
+        if !ends_word { continue }
+        if !word.is_empty() {
+            let path = PathBuf::from(std::mem::take(&mut word));
+            if !seen_colon {
+                targets.push(path);
+            }
+            //`|` introduces order-only prerequisites, which still count
+            else if path != Path::new("|") {
+                prerequisites.push(path);
+            }
+        }
+        match c {
+            Some(':') => {
+                if seen_colon { return Err(error(line, "Unexpected ':' after prerequisites")) }
+                if targets.is_empty() { return Err(error(line, "Rule has no targets")) }
+                seen_colon = true;
+            }
+            None | Some('\n') => {
+                if seen_colon {
+                    rules.push(DepfileRule { targets: std::mem::take(&mut targets), prerequisites: std::mem::take(&mut prerequisites) });
+                    seen_colon = false;
                 }
-                else {
-                    out.push(PathBuf::from_str(&current_str).unwrap());
-                    current_str = String::new();
+                else if !targets.is_empty() {
+                    return Err(error(line, "Expected ':' after targets"))
                 }
+                if c.is_none() { break }
+                line += 1;
             }
-            '\\' => {
-                if !escaped {
-                    escaped = true;
+            _ => {}
+        }
+    }
+    Ok(rules)
+}
+
+fn parse_show_includes(output: &str) -> Vec<PathBuf> {
+    const PREFIX: &str = "Note: including file:";
+    output.lines().filter_map(|l| l.strip_prefix(PREFIX)).map(|p| PathBuf::from(p.trim())).collect()
+}
+
+fn parse_json_list(json: &str) -> Result<Vec<PathBuf>, DepfileError> {
+    let chars: Vec<char> = json.chars().collect();
+    let mut i = 0;
+    //for error messages
+    let line_at = |i: usize| chars[..i.min(chars.len())].iter().filter(|c| **c == '\n').count() + 1;
+    let error = |i: usize, message: &str| DepfileError { line: line_at(i), message: message.to_owned() };
+    let skip_whitespace = |i: &mut usize| while chars.get(*i).is_some_and(|c| c.is_whitespace()) { *i += 1; };
+    skip_whitespace(&mut i);
+    if chars.get(i) != Some(&'[') { return Err(error(i, "Expected a JSON array")) }
+    i += 1;
+    let mut out = Vec::new();
+    loop {
+        skip_whitespace(&mut i);
+        match chars.get(i) {
+            Some(']') if out.is_empty() => { i += 1; break }
+            Some('"') => {
+                i += 1;
+                let mut path = String::new();
+                loop {
+                    let c = *chars.get(i).ok_or_else(|| error(i, "Unterminated string"))?;
+                    i += 1;
+                    match c {
+                        '"' => break,
+                        '\\' => {
+                            let escape = *chars.get(i).ok_or_else(|| error(i, "Unterminated string"))?;
+                            i += 1;
+                            path.push(match escape {
+                                '"' | '\\' | '/' => escape,
+                                'b' => '\u{8}',
+                                'f' => '\u{c}',
+                                'n' => '\n',
+                                'r' => '\r',
+                                't' => '\t',
+                                'u' => {
+                                    let unit = |i: &mut usize| {
+                                        let hex: String = chars.get(*i..*i + 4).ok_or_else(|| error(*i, "Truncated \\u escape"))?.iter().collect();
+                                        *i += 4;
+                                        u32::from_str_radix(&hex, 16).map_err(|_| error(*i, "Invalid \\u escape"))
+                                    };
+                                    let first = unit(&mut i)?;
+                                    let code = if (0xD800..0xDC00).contains(&first) {
+                                        //surrogate pair
+                                        if chars.get(i..i + 2) != Some(&['\\', 'u']) { return Err(error(i, "Unpaired surrogate")) }
+                                        i += 2;
+                                        let second = unit(&mut i)?;
+                                        0x10000 + ((first - 0xD800) << 10) + (second.wrapping_sub(0xDC00) & 0x3FF)
+                                    } else { first };
+                                    char::from_u32(code).ok_or_else(|| error(i, "Invalid \\u escape"))?
+                                }
+                                other => return Err(error(i, &format!("Unknown escape \\{}", other)))
+                            });
+                        }
+                        other => path.push(other),
+                    }
                 }
-                else { // matching '\\'
-                    escaped = false;
-                    current_str.push('\\');
+                out.push(PathBuf::from(path));
+                skip_whitespace(&mut i);
+                match chars.get(i) {
+                    Some(',') => i += 1,
+                    Some(']') => { i += 1; break }
+                    _ => return Err(error(i, "Expected ',' or ']'"))
                 }
-
             }
-            '\n' => {
-                escaped = false;
-            }
-            other => {
-                assert!(!escaped, "Unknown escape sequence \\{}",other);
-                current_str.push(other);
+            _ => return Err(error(i, "Expected a string"))
+        }
+    }
+    skip_whitespace(&mut i);
+    if i < chars.len() { return Err(error(i, "Unexpected text after the array")) }
+    Ok(out)
+}
+
+/**
+Parses a Ninja deps log.
+
+The log is `# ninjadeps\n`, a version (3 or 4), then records, each starting with a little-endian `u32` size whose
+high bit marks a deps record.  Path records are a path padded to 4 bytes and the complement of its id.  Deps records are
+the id of an output, its mtime (4 bytes in version 3, 8 in version 4), and the ids of its inputs.  Later deps records
+for the same output replace earlier ones.
+
+Returns the inputs of `output`, or of the only output in the log if it doesn't name `output`.
+*/
+fn parse_ninja_deps(log: &[u8], output: &Path) -> Result<Vec<PathBuf>, DepfileError> {
+    const MAGIC: &[u8] = b"# ninjadeps\n";
+    let error = |record, message: &str| DepfileError { line: record, message: message.to_owned() };
+    let u32_at = |bytes: &[u8], at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+    if !log.starts_with(MAGIC) || log.len() < MAGIC.len() + 4 { return Err(error(0, "Not a ninja deps log")) }
+    let version = u32_at(log, MAGIC.len());
+    let mtime_size = match version {
+        3 => 4,
+        4 => 8,
+        _ => return Err(error(0, &format!("Unsupported ninja deps version {}", version)))
+    };
+    let mut paths: Vec<PathBuf> = Vec::new();
+    //inputs of each output, by output id, in the order outputs first appear
+    let mut deps: Vec<(u32, Vec<u32>)> = Vec::new();
+    let mut at = MAGIC.len() + 4;
+    let mut record = 0;
+    while at < log.len() {
+        record += 1;
+        if at + 4 > log.len() { return Err(error(record, "Truncated record")) }
+        let header = u32_at(log, at);
+        let size = (header & 0x7FFF_FFFF) as usize;
+        at += 4;
+        let Some(body) = log.get(at..at + size) else { return Err(error(record, "Truncated record")) };
+        at += size;
+        if !size.is_multiple_of(4) { return Err(error(record, "Record size is not a multiple of 4")) }
+        if header & 0x8000_0000 != 0 {
+            if size < 4 + mtime_size { return Err(error(record, "Deps record is too short")) }
+            let output = u32_at(body, 0);
+            let inputs: Vec<u32> = body[4 + mtime_size..].chunks(4).map(|c| u32_at(c, 0)).collect();
+            match deps.iter_mut().find(|(o, _)| *o == output) {
+                Some(existing) => existing.1 = inputs,
+                None => deps.push((output, inputs)),
             }
         }
+        else {
+            if size < 4 { return Err(error(record, "Path record is too short")) }
+            let checksum = u32_at(body, size - 4);
+            if !checksum != paths.len() as u32 { return Err(error(record, "Path record has a bad checksum")) }
+            let path = &body[..size - 4];
+            let len = path.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+            let path = std::str::from_utf8(&path[..len]).map_err(|_| error(record, "Path is not UTF-8"))?;
+            paths.push(PathBuf::from(path));
+        }
     }
-    out.push(PathBuf::from_str(&current_str).unwrap());
-    out
+    let inputs = match deps.iter().find(|(o, _)| paths.get(*o as usize).is_some_and(|p| p == output)) {
+        Some((_, inputs)) => inputs,
+        None => match deps.as_slice() {
+            [] => return Ok(Vec::new()),
+            [(_, inputs)] => inputs,
+            _ => return Err(error(record, &format!("No deps record for {:?}", output)))
+        }
+    };
+    inputs.iter().map(|input| paths.get(*input as usize).cloned().ok_or_else(|| error(record, "Deps record names an unknown path"))).collect()
 }
 
 #[test] fn test_parse() {
     let txt = r#"depedencies: /Users/drew/Code/winspike/metal-build/tests/test.metal \
   /Users/drew/Code/winspike/metal-build/tests/example1.h \
   /Users/drew/Code/winspike/metal-build/tests/example\ 2.h"#;
-    let deps = parse(txt);
-    assert_eq!(deps[0], PathBuf::from_str("/Users/drew/Code/winspike/metal-build/tests/test.metal").unwrap());
-    assert_eq!(deps[1],  PathBuf::from_str("/Users/drew/Code/winspike/metal-build/tests/example1.h").unwrap());
-    assert_eq!(deps[2],  PathBuf::from_str("/Users/drew/Code/winspike/metal-build/tests/example 2.h").unwrap());
+    let rules = parse_depfile(txt).unwrap();
+    assert_eq!(rules.len(), 1);
+    let deps = &rules[0].prerequisites;
+    assert_eq!(deps[0], PathBuf::from("/Users/drew/Code/winspike/metal-build/tests/test.metal"));
+    assert_eq!(deps[1],  PathBuf::from("/Users/drew/Code/winspike/metal-build/tests/example1.h"));
+    assert_eq!(deps[2],  PathBuf::from("/Users/drew/Code/winspike/metal-build/tests/example 2.h"));
     assert_eq!(deps.len(), 3);
 }
 #[test] fn test_escaped() {
     let txt = r"C:\\path\\file.spirv: C:\\path\\file.o";
-    let _ = parse(txt);
-}
\ No newline at end of file
+    let rules = parse_depfile(txt).unwrap();
+    assert_eq!(rules, [DepfileRule { targets: vec![PathBuf::from(r"C:\path\file.spirv")], prerequisites: vec![PathBuf::from(r"C:\path\file.o")] }]);
+    let rules = parse_depfile("C:\\foo.o: C:\\foo.c D:/bar.h\r\n").unwrap();
+    assert_eq!(rules[0].targets, [PathBuf::from(r"C:\foo.o")]);
+    assert_eq!(rules[0].prerequisites, [PathBuf::from(r"C:\foo.c"), PathBuf::from("D:/bar.h")]);
+}
+#[test] fn test_rules() {
+    //clang -MD -MP, with tabs, comments and escapes
+    let txt = "# generated\nout/a.o out/a.d: src/a.c\tsrc/cost$$.h \\\n  src/\\#1.h\n\nsrc/cost$$.h:\n\nsrc/\\#1.h:\n";
+    let rules = parse_depfile(txt).unwrap();
+    assert_eq!(rules.len(), 3);
+    assert_eq!(rules[0].targets, [PathBuf::from("out/a.o"), PathBuf::from("out/a.d")]);
+    assert_eq!(rules[0].prerequisites, [PathBuf::from("src/a.c"), PathBuf::from("src/cost$.h"), PathBuf::from("src/#1.h")]);
+    assert_eq!(rules[1], DepfileRule { targets: vec![PathBuf::from("src/cost$.h")], prerequisites: vec![] });
+    assert_eq!(parse_depfile("").unwrap(), []);
+
+    assert_eq!(parse_depfile("a.o b.c\n").unwrap_err().line, 1);
+    assert_eq!(parse_depfile("a.o: b.c\n: c.h\n").unwrap_err().line, 2);
+    assert_eq!(parse_depfile("a.o: b.c: c.h").unwrap_err().message, "Unexpected ':' after prerequisites");
+}
+#[test] fn test_formats() {
+    let dir = std::env::temp_dir();
+    let read = |name: &str, contents: &[u8], format| {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        read_dependencies(&path, Path::new("out/a.spv"), format).map(Option::unwrap)
+    };
+    let expected = [PathBuf::from("src/a.frag"), PathBuf::from("src/common \"x\".h")];
+    assert_eq!(read("buildkit-deps.d", b"out/a.spv: src/a.frag src/common\\ \"x\".h src/a.frag", DependencyFormat::Makefile).unwrap(), expected);
+    assert_eq!(read("buildkit-deps.txt", b"src/a.frag\r\nsrc/common \"x\".h\n\n", DependencyFormat::PlainList).unwrap(), expected);
+    assert_eq!(read("buildkit-deps.json", br#" [ "src/a.frag", "src/common \"x\".h", "src\/a.frag" ] "#, DependencyFormat::Json).unwrap(), expected);
+    assert_eq!(read("buildkit-deps.json", br#"["\u00e9\ud83d\ude00"]"#, DependencyFormat::Json).unwrap(), [PathBuf::from("\u{e9}\u{1f600}")]);
+    assert!(matches!(read("buildkit-deps.json", b"[\"a\",\n 3]", DependencyFormat::Json), Err(BuildError::Depfile { error: DepfileError { line: 2, .. }, .. })));
+    let show_includes = b"a.cpp\r\nNote: including file: src/a.frag\r\nNote: including file:  src/common \"x\".h\r\n";
+    assert_eq!(read("buildkit-deps.showincludes", show_includes, DependencyFormat::MsvcShowIncludes).unwrap(), expected);
+
+    let mut log = b"# ninjadeps\n".to_vec();
+    log.extend(4u32.to_le_bytes());
+    for (id, path) in ["out/a.spv", "src/a.frag", "src/common \"x\".h", "src/old.h", "out/b.spv"].iter().enumerate() {
+        let mut body = path.as_bytes().to_vec();
+        while !body.len().is_multiple_of(4) { body.push(0); }
+        body.extend((!(id as u32)).to_le_bytes());
+        log.extend((body.len() as u32).to_le_bytes());
+        log.extend(body);
+    }
+    //a stale record, then the current one
+    for inputs in [&[3u32][..], &[1, 2]] {
+        let mut body = 0u32.to_le_bytes().to_vec();
+        body.extend(0u64.to_le_bytes());
+        for input in inputs { body.extend(input.to_le_bytes()); }
+        log.extend((body.len() as u32 | 0x8000_0000).to_le_bytes());
+        log.extend(body);
+    }
+    assert_eq!(read("buildkit-deps.ninja_deps", &log, DependencyFormat::NinjaDeps).unwrap(), expected);
+    assert!(read("buildkit-deps.ninja_deps", &log[..log.len() - 2], DependencyFormat::NinjaDeps).is_err());
+    //only this output's inputs count
+    let mut other = 4u32.to_le_bytes().to_vec();
+    other.extend(0u64.to_le_bytes());
+    other.extend(3u32.to_le_bytes());
+    log.extend((other.len() as u32 | 0x8000_0000).to_le_bytes());
+    log.extend(other);
+    assert_eq!(read("buildkit-deps.ninja_deps", &log, DependencyFormat::NinjaDeps).unwrap(), expected);
+    let path = dir.join("buildkit-deps.ninja_deps");
+    assert_eq!(read_dependencies(&path, Path::new("out/b.spv"), DependencyFormat::NinjaDeps).unwrap().unwrap(), [PathBuf::from("src/old.h")]);
+    assert!(read_dependencies(&path, Path::new("out/c.spv"), DependencyFormat::NinjaDeps).is_err());
+    assert_eq!(read_dependencies(&dir.join("buildkit-deps.missing"), Path::new("out/a.spv"), DependencyFormat::Makefile).unwrap(), None);
+}
+
+#[test] fn rerun_lines() {
+    let paths: BTreeSet<PathBuf> = ["src/b.frag", "src/a b.frag", "src/b.frag", "bad\nname"].iter().map(PathBuf::from).collect();
+    let lines: Vec<_> = rerun_if_changed_lines(&paths).collect();
+    assert_eq!(lines, [Err(Path::new("bad\nname")), Ok("cargo:rerun-if-changed=src/a b.frag".to_owned()), Ok("cargo:rerun-if-changed=src/b.frag".to_owned())]);
+}
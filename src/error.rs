@@ -0,0 +1,144 @@
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::process::ExitStatus;
+
+///Which kind of step failed.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum StepKind {
+    ///A [crate::TryCompileStep]
+    Compile,
+    ///A [crate::TryLinkStep]
+    Link,
+}
+
+///Describes a failed compile or link step.
+///
+/// Typically created by a [crate::TryCompileStep] or [crate::TryLinkStep] when the tool it runs fails.
+#[derive(Clone,Debug)]
+#[non_exhaustive]
+pub struct StepError {
+    pub kind: StepKind,
+    ///The sourcefile being compiled.  This is `None` for link steps.
+    ///
+    /// If a compile step leaves this empty, buildkit fills it in.
+    pub source: Option<PathBuf>,
+    ///Exit status of the tool, if it ran.
+    pub status: Option<ExitStatus>,
+    ///Captured standard output of the tool
+    pub stdout: String,
+    ///Captured standard error of the tool
+    pub stderr: String,
+    ///Human-readable description of what went wrong
+    pub message: String,
+    ///The command line that failed, if the step ran a tool.
+    pub command: Option<String>,
+}
+
+impl StepError {
+    pub fn new(kind: StepKind, message: impl Into<String>) -> Self {
+        StepError { kind, source: None, status: None, stdout: String::new(), stderr: String::new(), message: message.into(), command: None }
+    }
+    pub fn with_source(mut self, source: impl Into<PathBuf>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+    pub fn with_status(mut self, status: ExitStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+    ///Records the status and output of a tool, e.g. from [std::process::Command::output].
+    pub fn with_output(mut self, output: &std::process::Output) -> Self {
+        self.status = Some(output.status);
+        self.stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        self.stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        self
+    }
+    ///Records the command line that failed.
+    pub fn with_command(mut self, command: &std::process::Command) -> Self {
+        self.command = Some(crate::command::command_line(command));
+        self
+    }
+}
+
+impl Display for StepError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (self.kind, &self.source) {
+            (StepKind::Compile, Some(source)) => write!(f, "Compiling {:?} failed: {}", source, self.message)?,
+            (StepKind::Compile, None) => write!(f, "Compile failed: {}", self.message)?,
+            (StepKind::Link, _) => write!(f, "Link failed: {}", self.message)?,
+        }
+        if let Some(status) = self.status {
+            write!(f, " ({})", status)?;
+        }
+        if let Some(command) = &self.command {
+            write!(f, "\n{}", command)?;
+        }
+        for output in [&self.stdout, &self.stderr] {
+            if !output.trim().is_empty() {
+                write!(f, "\n{}", output.trim_end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+///Errors returned by [crate::CompileSystem] and [crate::BuildSystem].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BuildError {
+    ///A compile or link step failed.
+    Step(Box<StepError>),
+    ///Buildkit could not read or write a file it manages.
+    Io { path: PathBuf, error: std::io::Error },
+    ///Several steps failed.  See [crate::CompileSettingsBuilder::keep_going].
+    Multiple(Vec<BuildError>),
+    ///Two sourcefiles produce the same output file, so one overwrote the other.
+    ///
+    /// Outputs are only known after compiling, so this is checked once every sourcefile is compiled.  Neither output is
+    /// cached or remembered for incremental builds.  Consider [crate::mirror_intermediate_file] in your compile step, or [crate::CompileStep::MIRROR_INTERMEDIATE_DIR].
+    OutputCollision { output: PathBuf, first: PathBuf, second: PathBuf },
+    ///A dependency file could not be parsed.
+    Depfile { path: PathBuf, error: crate::DepfileError },
+    ///While searching for sourcefiles, we found a symlink to a directory that contains it.
+    SymlinkLoop { path: PathBuf },
+    ///While searching for sourcefiles, we found a symlink, and [crate::SymlinkPolicy::Error] is in effect.
+    SymlinkNotAllowed { path: PathBuf },
+    ///We could not get a token from cargo's jobserver to run a compile.
+    Jobserver(std::io::Error),
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::Step(step) => step.fmt(f),
+            BuildError::Io { path, error } => write!(f, "Problem accessing {:?}: {}", path, error),
+            BuildError::Multiple(errors) => {
+                write!(f, "{} steps failed", errors.len())?;
+                for error in errors {
+                    write!(f, "\n\n{}", error)?;
+                }
+                Ok(())
+            }
+            BuildError::Depfile { path, error } => write!(f, "Problem parsing dependency file {:?}, {}", path, error),
+            BuildError::OutputCollision { output, first, second } => write!(f, "{:?} and {:?} both produce {:?}", first, second, output),
+            BuildError::SymlinkLoop { path } => write!(f, "Symlink loop while searching for sourcefiles at {:?}", path),
+            BuildError::SymlinkNotAllowed { path } => write!(f, "Found symlink while searching for sourcefiles at {:?}", path),
+            BuildError::Jobserver(error) => write!(f, "Problem acquiring a jobserver token: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::Io { error, .. } => Some(error),
+            BuildError::Depfile { error, .. } => Some(error),
+            BuildError::Jobserver(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<StepError> for BuildError {
+    fn from(error: StepError) -> Self { BuildError::Step(Box::new(error)) }
+}
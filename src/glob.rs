@@ -0,0 +1,127 @@
+use std::path::{Component, Path, PathBuf};
+
+///A glob pattern like `shaders/**/*.hlsl`.
+///
+/// Supports `*` (any characters within one path component), `?` (any one character), `[abc]`, `[a-z]` and `[!abc]`
+/// (one character from a set), and `**` (any number of whole path components).
+#[derive(Clone,Debug)]
+pub(crate) struct Glob {
+    ///The leading components without any wildcards.  Only paths under this directory can match.
+    root: PathBuf,
+    ///The remaining components, matched against the path relative to `root`.
+    pattern: Vec<String>,
+}
+
+impl Glob {
+    ///Creates a glob.  Relative patterns are resolved against `base`.
+    pub(crate) fn new(base: &Path, pattern: &str) -> Glob {
+        let mut root = base.to_path_buf();
+        let mut components = Vec::new();
+        for component in Path::new(pattern).components() {
+            match component {
+                Component::Normal(part) if !components.is_empty() || is_wildcard(&part.to_string_lossy()) => {
+                    components.push(part.to_string_lossy().into_owned());
+                }
+                literal => {
+                    if components.is_empty() {
+                        root.push(literal);
+                    }
+                    else {
+                        components.push(literal.as_os_str().to_string_lossy().into_owned());
+                    }
+                }
+            }
+        }
+        Glob { root, pattern: components }
+    }
+
+    ///The directory to search for matches.  If the pattern has no wildcards, this is the only path that can match.
+    pub(crate) fn root(&self) -> &Path { &self.root }
+
+    pub(crate) fn matches(&self, path: &Path) -> bool {
+        let Ok(relative) = path.strip_prefix(&self.root) else { return false };
+        let components: Vec<_> = relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+        let components: Vec<&str> = components.iter().map(|c| c.as_str()).collect();
+        let pattern: Vec<&str> = self.pattern.iter().map(|c| c.as_str()).collect();
+        match_components(&pattern, &components)
+    }
+}
+
+fn is_wildcard(component: &str) -> bool {
+    component.contains(['*', '?', '['])
+}
+
+fn match_components(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_components(rest, &path[skip..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((component, path_rest)) => {
+                let pattern: Vec<char> = first.chars().collect();
+                let component: Vec<char> = component.chars().collect();
+                match_component(&pattern, &component) && match_components(rest, path_rest)
+            }
+            None => false
+        }
+    }
+}
+
+fn match_component(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => (0..=text.len()).any(|skip| match_component(&pattern[1..], &text[skip..])),
+        Some('?') => !text.is_empty() && match_component(&pattern[1..], &text[1..]),
+        Some('[') => {
+            let Some(close) = pattern.iter().skip(2).position(|c| *c == ']').map(|p| p + 2) else {
+                //unterminated set; treat the bracket literally
+                return text.first() == Some(&'[') && match_component(&pattern[1..], &text[1..])
+            };
+            let Some(c) = text.first() else { return false };
+            let (negated, set) = match pattern[1] {
+                '!' | '^' => (true, &pattern[2..close]),
+                _ => (false, &pattern[1..close]),
+            };
+            let mut in_set = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == '-' {
+                    in_set |= set[i] <= *c && *c <= set[i + 2];
+                    i += 3;
+                }
+                else {
+                    in_set |= set[i] == *c;
+                    i += 1;
+                }
+            }
+            in_set != negated && match_component(&pattern[close + 1..], &text[1..])
+        }
+        Some(literal) => text.first() == Some(literal) && match_component(&pattern[1..], &text[1..]),
+    }
+}
+
+#[test] fn glob_matching() {
+    let base = Path::new("/project");
+    let hlsl = Glob::new(base, "shaders/**/*.hlsl");
+    assert_eq!(hlsl.root(), Path::new("/project/shaders"));
+    assert!(hlsl.matches(Path::new("/project/shaders/a.hlsl")));
+    assert!(hlsl.matches(Path::new("/project/shaders/ui/deep/b.hlsl")));
+    assert!(!hlsl.matches(Path::new("/project/shaders/a.glsl")));
+    assert!(!hlsl.matches(Path::new("/project/other/a.hlsl")));
+
+    let tests = Glob::new(base, "shaders/**/*_test.hlsl");
+    assert!(tests.matches(Path::new("/project/shaders/ui/a_test.hlsl")));
+    assert!(!tests.matches(Path::new("/project/shaders/ui/a.hlsl")));
+
+    let vendor = Glob::new(base, "shaders/vendor/**");
+    assert!(vendor.matches(Path::new("/project/shaders/vendor/x/y.hlsl")));
+    assert!(!vendor.matches(Path::new("/project/shaders/a.hlsl")));
+
+    let sets = Glob::new(base, "[a-c]?.[!x]*");
+    assert!(sets.matches(Path::new("/project/b1.hlsl")));
+    assert!(!sets.matches(Path::new("/project/d1.hlsl")));
+    assert!(!sets.matches(Path::new("/project/b1.xyz")));
+
+    let absolute = Glob::new(base, "/elsewhere/*.frag");
+    assert_eq!(absolute.root(), Path::new("/elsewhere"));
+    assert!(absolute.matches(Path::new("/elsewhere/a.frag")));
+}
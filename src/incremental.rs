@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::{BuildError, Configuration};
+use crate::content_hash::{ContentHash, ContentHasher, FileHashes};
+
+///How to decide whether a sourcefile needs to be compiled again.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Incremental {
+    ///Compile every sourcefile, every time.
+    Disabled,
+    ///Skip sourcefiles whose object file is newer than the sourcefile and every dependency it listed
+    /// in its dependency file on the previous build, as long as the flags, configuration and
+    /// [crate::CompileStep::tool_version] are the same as they were then.
+    Timestamps,
+    ///Skip sourcefiles when the contents of the sourcefile and every dependency it listed on the previous build,
+    /// as well as the flags, configuration and [crate::CompileStep::tool_version], are the same as they were then.
+    ///
+    /// Unlike [Incremental::Timestamps], this is unaffected by checkouts or caches that reset modification times.
+    ContentHash,
+}
+
+///Bump this whenever the database format changes, so databases from older versions are discarded.
+const DATABASE_VERSION: u32 = 3;
+const DATABASE_MAGIC: &str = "buildkit-db";
+
+///What we learned about a sourcefile the last time we compiled it.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub(crate) struct CompileRecord {
+    pub(crate) output: PathBuf,
+    pub(crate) dependencies: Vec<PathBuf>,
+    ///Contents of the sourcefile and each dependency.  Only recorded for [Incremental::ContentHash].
+    pub(crate) input_hashes: HashMap<PathBuf, ContentHash>,
+    ///Fingerprint of the flags, configuration and tool version the output was compiled with.
+    pub(crate) settings: Option<ContentHash>,
+    ///Contents of the output.  Only recorded for [Incremental::ContentHash], or with [crate::CompileSettingsBuilder::restat].
+    pub(crate) output_hash: Option<ContentHash>,
+    ///Where the compile step was asked to write its dependency file, so we can remove it along with the output.
+    pub(crate) dependency_file: Option<PathBuf>,
+}
+
+impl CompileRecord {
+    pub(crate) fn new(output: PathBuf, dependencies: Vec<PathBuf>) -> Self {
+        CompileRecord { output, dependencies, input_hashes: HashMap::new(), settings: None, output_hash: None, dependency_file: None }
+    }
+
+    ///Remembers the current contents of the sourcefile and its dependencies.
+    pub(crate) fn record_hashes(&mut self, source: &Path, hashes: &FileHashes) {
+        for input in std::iter::once(source).chain(self.dependencies.iter().map(|d| d.as_path())) {
+            //an input we can't read won't match next time, so the sourcefile will be compiled again
+            if let Some(hash) = hashes.get(input) {
+                self.input_hashes.insert(input.to_path_buf(), hash);
+            }
+        }
+    }
+
+    ///Whether compiling again would produce the same thing.
+    pub(crate) fn is_up_to_date(&self, source: &Path, incremental: Incremental, settings: ContentHash, hashes: &FileHashes) -> bool {
+        let mut inputs = std::iter::once(source).chain(self.dependencies.iter().map(|d| d.as_path()));
+        if self.settings != Some(settings) {
+            return false
+        }
+        match incremental {
+            Incremental::Disabled => false,
+            Incremental::Timestamps => {
+                let Some(output_time) = modified(&self.output) else { return false };
+                inputs.all(|input| {
+                    match modified(input) {
+                        Some(input_time) => input_time <= output_time,
+                        //input is missing, so the compile will have to find out what happened
+                        None => false
+                    }
+                })
+            }
+            Incremental::ContentHash => {
+                self.output.exists() && inputs.all(|input| {
+                    match (self.input_hashes.get(input), hashes.get(input)) {
+                        (Some(previous), Some(current)) => *previous == current,
+                        _ => false
+                    }
+                })
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+///Fingerprint of the settings that affect every output, including the compiler's [crate::CompileTool::cache_identity].
+pub(crate) fn settings_fingerprint(flags: &[String], configuration: Configuration, tool_version: Option<&str>, cache_identity: &str) -> ContentHash {
+    let mut hasher = ContentHasher::new();
+    hasher.write_field(cache_identity.as_bytes());
+    hasher.write_field(format!("{:?}", configuration).as_bytes());
+    hasher.write_field(format!("{:?}", tool_version).as_bytes());
+    for flag in flags {
+        hasher.write_field(flag.as_bytes());
+    }
+    hasher.finish()
+}
+
+///Records about every sourcefile from the previous build, stored in the intermediate directory.
+#[derive(Default,Debug)]
+pub(crate) struct BuildDatabase {
+    pub(crate) records: HashMap<PathBuf, CompileRecord>,
+}
+
+impl BuildDatabase {
+    ///Each compile step gets its own database, named after its identity and extensions,
+    /// so steps sharing an intermediate directory don't treat each other's outputs as orphans.
+    pub(crate) fn path(intermediate_path: &Path, cache_identity: &str, extensions: &[&str]) -> PathBuf {
+        let mut hasher = ContentHasher::new();
+        hasher.write_field(cache_identity.as_bytes());
+        for extension in extensions {
+            hasher.write_field(extension.as_bytes());
+        }
+        intermediate_path.join(format!("buildkit-{}.db", hasher.finish()))
+    }
+
+    ///Reads the database.  If it is missing, unreadable, or from another version, returns an empty database, so everything gets built.
+    pub(crate) fn load(path: &Path) -> BuildDatabase {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => Self::parse(&contents).unwrap_or_default(),
+            Err(_) => BuildDatabase::default()
+        }
+    }
+
+    fn parse(contents: &str) -> Option<BuildDatabase> {
+        let mut lines = contents.lines();
+        if lines.next()? != format!("{}\t{}", DATABASE_MAGIC, DATABASE_VERSION) {
+            return None
+        }
+        let mut records = HashMap::new();
+        let mut current: Option<(PathBuf, CompileRecord)> = None;
+        for line in lines {
+            let fields: Vec<_> = line.split('\t').collect();
+            match fields.as_slice() {
+                ["source", source] => {
+                    if let Some((source, record)) = current.take() {
+                        records.insert(source, record);
+                    }
+                    current = Some((unescape_path(source)?, CompileRecord::new(PathBuf::new(), Vec::new())));
+                }
+                ["output", output] => current.as_mut()?.1.output = unescape_path(output)?,
+                ["dependency", dependency] => current.as_mut()?.1.dependencies.push(unescape_path(dependency)?),
+                ["hash", path, hash] => {
+                    current.as_mut()?.1.input_hashes.insert(unescape_path(path)?, ContentHash::parse(hash)?);
+                }
+                ["settings", hash] => current.as_mut()?.1.settings = Some(ContentHash::parse(hash)?),
+                ["output-hash", hash] => current.as_mut()?.1.output_hash = Some(ContentHash::parse(hash)?),
+                ["depfile", path] => current.as_mut()?.1.dependency_file = Some(unescape_path(path)?),
+                _ => return None
+            }
+        }
+        if let Some((source, record)) = current.take() {
+            records.insert(source, record);
+        }
+        Some(BuildDatabase { records })
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<(), BuildError> {
+        let mut contents = format!("{}\t{}\n", DATABASE_MAGIC, DATABASE_VERSION);
+        //sorted, so the file doesn't churn between builds
+        let mut sources: Vec<_> = self.records.keys().collect();
+        sources.sort();
+        for source in sources {
+            //a path we can't write down will just be compiled again next time
+            if let Some(entry) = Self::entry(source, &self.records[source]) {
+                contents.push_str(&entry);
+            }
+        }
+        std::fs::write(path, contents).map_err(|error| BuildError::Io { path: path.to_path_buf(), error })
+    }
+
+    fn entry(source: &Path, record: &CompileRecord) -> Option<String> {
+        let mut entry = format!("source\t{}\n", escape(source.to_str()?));
+        entry.push_str(&format!("output\t{}\n", escape(record.output.to_str()?)));
+        for dependency in &record.dependencies {
+            entry.push_str(&format!("dependency\t{}\n", escape(dependency.to_str()?)));
+        }
+        let mut hashes: Vec<_> = record.input_hashes.iter().collect();
+        hashes.sort_by_key(|(path, _)| *path);
+        for (path, hash) in hashes {
+            entry.push_str(&format!("hash\t{}\t{}\n", escape(path.to_str()?), hash));
+        }
+        if let Some(settings) = record.settings {
+            entry.push_str(&format!("settings\t{}\n", settings));
+        }
+        if let Some(output_hash) = record.output_hash {
+            entry.push_str(&format!("output-hash\t{}\n", output_hash));
+        }
+        if let Some(dependency_file) = &record.dependency_file {
+            entry.push_str(&format!("depfile\t{}\n", escape(dependency_file.to_str()?)));
+        }
+        Some(entry)
+    }
+}
+
+const LINK_DATABASE_MAGIC: &str = "buildkit-link";
+
+///What we linked on the previous build.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub(crate) struct LinkRecord {
+    ///Fingerprint of everything [LinkRecord::fingerprint] covers
+    pub(crate) inputs: ContentHash,
+    ///What the link step returned
+    pub(crate) product: PathBuf,
+}
+
+impl LinkRecord {
+    ///Named after the product, so products sharing an intermediate directory are tracked separately.
+    pub(crate) fn path(intermediate_path: &Path, product_name: &str) -> PathBuf {
+        let mut hasher = ContentHasher::new();
+        hasher.write_field(product_name.as_bytes());
+        intermediate_path.join(format!("buildkit-link-{}.db", hasher.finish()))
+    }
+
+    ///Fingerprint of the object files and the settings that affect the link.
+    ///
+    /// Object files are identified by their recorded [CompileRecord::output_hash] if there is one, otherwise by their modification times.
+    /// So with hashes, an object that was compiled again but came out the same doesn't cause a link.
+    pub(crate) fn fingerprint(objects: &[CompileRecord], product_name: &str, output_dir: &Path, configuration: Configuration) -> ContentHash {
+        let mut hasher = ContentHasher::new();
+        hasher.write_field(product_name.as_bytes());
+        hasher.write_field(output_dir.as_os_str().as_encoded_bytes());
+        hasher.write_field(format!("{:?}", configuration).as_bytes());
+        for object in objects {
+            hasher.write_field(object.output.as_os_str().as_encoded_bytes());
+            //a missing object hashes differently from any present one, so we link and let the linker complain
+            let stamp = match object.output_hash {
+                Some(hash) => hash.to_string(),
+                None => format!("{:?}", modified(&object.output)),
+            };
+            hasher.write_field(stamp.as_bytes());
+        }
+        hasher.finish()
+    }
+
+    ///Reads the record.  Returns `None` if it is missing, unreadable or from another version, so we link.
+    pub(crate) fn load(path: &Path) -> Option<LinkRecord> {
+        let contents = std::fs::read_to_string(path).ok()?;
+        let mut lines = contents.lines();
+        if lines.next()? != format!("{}\t{}", LINK_DATABASE_MAGIC, DATABASE_VERSION) {
+            return None
+        }
+        let (mut inputs, mut product) = (None, None);
+        for line in lines {
+            match line.split('\t').collect::<Vec<_>>().as_slice() {
+                ["inputs", hash] => inputs = Some(ContentHash::parse(hash)?),
+                ["product", path] => product = Some(unescape_path(path)?),
+                _ => return None
+            }
+        }
+        Some(LinkRecord { inputs: inputs?, product: product? })
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<(), BuildError> {
+        let mut contents = format!("{}\t{}\ninputs\t{}\n", LINK_DATABASE_MAGIC, DATABASE_VERSION, self.inputs);
+        //a product we can't write down will just be linked again next time
+        if let Some(product) = self.product.to_str() {
+            contents.push_str(&format!("product\t{}\n", escape(product)));
+        }
+        std::fs::write(path, contents).map_err(|error| BuildError::Io { path: path.to_path_buf(), error })
+    }
+}
+
+pub(crate) fn unescape_path(field: &str) -> Option<PathBuf> {
+    unescape(field).map(PathBuf::from)
+}
+
+pub(crate) fn escape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            other => out.push(other)
+        }
+    }
+    out
+}
+
+fn unescape(field: &str) -> Option<String> {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next()? {
+                '\\' => out.push('\\'),
+                't' => out.push('\t'),
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                _ => return None
+            }
+        }
+        else {
+            out.push(c);
+        }
+    }
+    Some(out)
+}
+
+#[test] fn database_roundtrip() {
+    let mut database = BuildDatabase::default();
+    let mut record = CompileRecord::new(PathBuf::from("out/a.spv"), vec![PathBuf::from("src/a\tb.frag"), PathBuf::from("src\\common.h")]);
+    record.input_hashes.insert(PathBuf::from("src/a\tb.frag"), ContentHash::parse("0123456789abcdef0123456789abcdef").unwrap());
+    record.settings = Some(settings_fingerprint(&["-O".to_owned()], Configuration::Release, Some("1.0"), "copy"));
+    record.output_hash = ContentHash::parse("fedcba9876543210fedcba9876543210");
+    record.dependency_file = Some(PathBuf::from("out/a.frag.d"));
+    database.records.insert(PathBuf::from("src/a\tb.frag"), record);
+    database.records.insert(PathBuf::from("src/c.frag"), CompileRecord::new(PathBuf::from("out/c.spv"), Vec::new()));
+    let mut path = std::env::temp_dir();
+    path.push("buildkit-database-roundtrip.db");
+    database.save(&path).unwrap();
+    let loaded = BuildDatabase::load(&path);
+    assert_eq!(loaded.records, database.records);
+
+    //databases from another version are discarded
+    let contents = std::fs::read_to_string(&path).unwrap().replacen(&DATABASE_VERSION.to_string(), "0", 1);
+    std::fs::write(&path, contents).unwrap();
+    assert!(BuildDatabase::load(&path).records.is_empty());
+}
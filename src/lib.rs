@@ -12,12 +12,12 @@ dependency by hand in `build.rs`, or wrestle the [cc](https://docs.rs/cc/1.0.71/
 2.  For this reason, the ordinary way to use buildkit is indirectly through some other crate that knows how to do what you want to do
     (or writing such a crate).
     In particular, if you're looking for a `cc` replacement, you want that other higher-level crate.
-3.  Skips builds if no sourcefiles were changed.  Other features like advanced incremental builds and parallel builds are planned.
+3.  Skips builds if no sourcefiles were changed.  Compiles sourcefiles in parallel, and can skip individual sourcefiles, and the link step, when they have not changed (see [Incremental]).
 
 ## Compared with writing shell yourself:
 
-1.  Buildkit skips builds if no sourcefiles were changed.  Other features like advanced incremental builds and parallel builds are planned.
-2.  Easy integration with `build.rs`, debug vs release profiles, where to locate intermediate object files, etc
+1.  Buildkit skips builds if no sourcefiles were changed.  Compiles sourcefiles in parallel, and can skip individual sourcefiles, and the link step, when they have not changed (see [Incremental]).
+2.  Easy integration with `build.rs`, debug vs release profiles, where to locate intermediate object files, etc.  Outputs can be shared between crates and worktrees through a local cache (see [CompileSettingsBuilder::cache_dir]).
 3.  Modeling both compile (1 sourcefile per output) and link (many sourcefiles per output).
 
 # Usage
@@ -41,12 +41,39 @@ fn build_rs(exe_path: PathBuf) {
 }
 ```
 
+If your compiler can be run from a single command line, implement [CommandTemplate] instead.
+
+If your compiler needs its own configuration (a tool path, include directories, etc.), implement [CompileTool]
+on a value holding it, and pass that value to [CompileSystem::build_with].  It receives a [CompileContext] describing each compile.
+
 Implement [LinkStep] if your usecase involves linking multiple compile files into a single result.
 
+To report failures instead of panicking, implement [TryCompileStep] (or [TryLinkStep]) and return a [StepError].
+[run_command] does this for you when your step runs a tool:
+```
+use buildkit::{BuildError,StepError,StepKind,TryCompileStep,Configuration};
+use std::path::{PathBuf,Path};
+struct MyCompiler;
+impl TryCompileStep for MyCompiler {
+    const SOURCE_FILE_EXTENSION: &'static str = "mylang";
+    fn try_compile_one<'a>(path: &Path, intermediate_dir: &Path, configuration: &Configuration, dependency_path: &Path,flags: impl Iterator<Item=&'a str>) -> Result<PathBuf,BuildError> {
+        let output = std::process::Command::new("mylangc").arg(path).output().unwrap();
+        if !output.status.success() {
+            return Err(StepError::new(StepKind::Compile, "mylangc failed").with_output(&output).into());
+        }
+        //or equivalently
+        buildkit::run_command(std::process::Command::new("mylangc").arg(path), StepKind::Compile)?;
+        todo!()
+    }
+}
+```
+
 ## Use-side
 
 Call [CompileSystem::build_rs] from a `build.rs` file.  See documentation for additional entrypoints and options.
 
+Builds return a [BuildError] when a step fails.
+
 
  */
 
@@ -56,9 +83,28 @@ mod build_system;
 mod dependency_parser;
 mod compile_system;
 mod compile_settings;
+mod incremental;
+mod content_hash;
+mod error;
+mod command;
+mod template;
+mod context;
+mod glob;
+mod walk;
+mod cache;
+#[cfg(test)]
+mod test_support;
 
 pub use build_settings::{BuildSettings,BuildSettingsBuilder,Configuration,PathType};
 pub use compile_settings::{CompileSettings,SourceFileStrategy,CompileSettingsBuilder};
 pub use build_system::BuildSystem;
 pub use compile_system::CompileSystem;
-pub use traits::{CompileStep,LinkStep,suggest_intermediate_file};
\ No newline at end of file
+pub use incremental::Incremental;
+pub use traits::{CompileStep,LinkStep,TryCompileStep,TryLinkStep,CompileTool,LinkTool,Static,suggest_intermediate_file,mirror_intermediate_file};
+pub use error::{BuildError,StepError,StepKind};
+pub use command::run_command;
+pub use template::{CommandTemplate,TemplateCompileStep};
+pub use context::{CompileContext,LinkContext,Log,TargetInfo};
+pub use walk::SymlinkPolicy;
+pub use cache::CacheStats;
+pub use dependency_parser::{DependencyFormat, DepfileError, DepfileRule, parse_depfile};
\ No newline at end of file
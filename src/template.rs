@@ -0,0 +1,194 @@
+use std::ffi::{OsStr, OsString};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{BuildError, Configuration, DependencyFormat, StepError, StepKind, TryCompileStep, run_command, suggest_intermediate_file};
+
+/**
+Describes a compiler that can be run from a single command line, so you don't need to implement [TryCompileStep] yourself.
+
+The [CommandTemplate::TEMPLATE] is split on whitespace into a program and its arguments, and these placeholders are substituted:
+
+* `{input}`: Path to the sourcefile
+* `{output}`: Path to the object file, from [suggest_intermediate_file] and [CommandTemplate::OUTPUT_EXTENSION], in a directory
+  mirroring where the sourcefile was found (see [crate::mirror_intermediate_file])
+* `{depfile}`: Path to the dependency file.  If the template doesn't use this, we write a dependency file listing just the sourcefile.
+* `{flags}`: Flags for the configuration (see [CommandTemplate::DEBUG_FLAGS]), followed by flags from [crate::CompileSettingsBuilder::set_flags].
+  This must be a whole argument, and expands to any number of arguments.
+
+Use it with [TemplateCompileStep]:
+```
+use buildkit::{CommandTemplate,CompileSystem,TemplateCompileStep};
+struct Glslc;
+impl CommandTemplate for Glslc {
+    const SOURCE_FILE_EXTENSION: &'static str = "frag";
+    const OUTPUT_EXTENSION: &'static str = "spv";
+    const TEMPLATE: &'static str = "glslc {input} -o {output} -MD -MF {depfile} {flags}";
+    const RELEASE_FLAGS: &'static [&'static str] = &["-O"];
+}
+fn build_rs(exe_path: std::path::PathBuf) {
+    let built_files = CompileSystem::<TemplateCompileStep<Glslc>>::build_rs(exe_path);
+}
+```
+*/
+pub trait CommandTemplate {
+    ///The extension to scan for.  See [crate::CompileStep::SOURCE_FILE_EXTENSION].
+    const SOURCE_FILE_EXTENSION: &'static str;
+    ///All the extensions to scan for.  See [crate::CompileStep::SOURCE_FILE_EXTENSIONS].
+    const SOURCE_FILE_EXTENSIONS: &'static [&'static str] = &[Self::SOURCE_FILE_EXTENSION];
+    ///Format of the file the tool writes to `{depfile}`.  See [crate::CompileStep::DEPENDENCY_FORMAT].
+    const DEPENDENCY_FORMAT: DependencyFormat = DependencyFormat::Makefile;
+    ///Extension for object files, e.g. `spv`
+    const OUTPUT_EXTENSION: &'static str;
+    ///The command line to run for each sourcefile.
+    const TEMPLATE: &'static str;
+    ///Flags for [Configuration::Debug] builds.
+    const DEBUG_FLAGS: &'static [&'static str] = &[];
+    ///Flags for [Configuration::Release] builds.
+    const RELEASE_FLAGS: &'static [&'static str] = &[];
+
+    ///Version of the tool.  See [crate::CompileStep::tool_version].
+    fn tool_version() -> Option<String> { None }
+}
+
+///A [TryCompileStep] that runs a [CommandTemplate].
+pub struct TemplateCompileStep<T: CommandTemplate> {
+    template: PhantomData<T>,
+}
+
+impl<T: CommandTemplate> TryCompileStep for TemplateCompileStep<T> {
+    const SOURCE_FILE_EXTENSION: &'static str = T::SOURCE_FILE_EXTENSION;
+    const SOURCE_FILE_EXTENSIONS: &'static [&'static str] = T::SOURCE_FILE_EXTENSIONS;
+    //when the template has no `{depfile}`, we write a Makefile one ourselves
+    const DEPENDENCY_FORMAT: DependencyFormat = if has_depfile(T::TEMPLATE) { T::DEPENDENCY_FORMAT } else { DependencyFormat::Makefile };
+    const MIRROR_INTERMEDIATE_DIR: bool = true;
+
+    fn try_compile_one<'a>(path: &Path, intermediate_dir: &Path, configuration: &Configuration, dependency_path: &Path, flags: impl Iterator<Item=&'a str>) -> Result<PathBuf, BuildError> {
+        let output = suggest_intermediate_file(path, intermediate_dir.to_path_buf(), OsStr::new(T::OUTPUT_EXTENSION));
+        let configuration_flags = match configuration {
+            Configuration::Debug => T::DEBUG_FLAGS,
+            Configuration::Release => T::RELEASE_FLAGS,
+        };
+        let flags: Vec<&str> = configuration_flags.iter().copied().chain(flags).collect();
+        let mut args = expand_template(T::TEMPLATE, path, &output, dependency_path, &flags).into_iter();
+        let program = args.next().ok_or_else(|| StepError::new(StepKind::Compile, "Command template is empty"))?;
+        if !has_depfile(T::TEMPLATE) {
+            //the tool won't tell us about dependencies, so the sourcefile is the only one we know about
+            let depfile = format!("{}: {}", escape_make(&output), escape_make(path));
+            std::fs::write(dependency_path, depfile).map_err(|error| BuildError::Io { path: dependency_path.to_path_buf(), error })?;
+        }
+        run_command(Command::new(program).args(args), StepKind::Compile)?;
+        Ok(output)
+    }
+
+    fn tool_version() -> Option<String> { T::tool_version() }
+}
+
+const fn has_depfile(template: &str) -> bool {
+    //`str::contains` isn't const
+    let (template, placeholder) = (template.as_bytes(), b"{depfile}");
+    let mut start = 0;
+    while start + placeholder.len() <= template.len() {
+        let mut i = 0;
+        while i < placeholder.len() && template[start + i] == placeholder[i] { i += 1; }
+        if i == placeholder.len() { return true }
+        start += 1;
+    }
+    false
+}
+
+fn escape_make(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "\\\\").replace(' ', "\\ ")
+}
+
+///Splits the template into arguments and substitutes placeholders.
+fn expand_template(template: &str, input: &Path, output: &Path, depfile: &Path, flags: &[&str]) -> Vec<OsString> {
+    let mut args = Vec::new();
+    for token in template.split_whitespace() {
+        if token == "{flags}" {
+            args.extend(flags.iter().map(OsString::from));
+            continue
+        }
+        let mut arg = OsString::new();
+        let mut rest = token;
+        while let Some(start) = rest.find('{') {
+            arg.push(&rest[..start]);
+            let after = &rest[start..];
+            let (replacement, len): (&OsStr, usize) = if after.starts_with("{input}") {
+                (input.as_os_str(), "{input}".len())
+            } else if after.starts_with("{output}") {
+                (output.as_os_str(), "{output}".len())
+            } else if after.starts_with("{depfile}") {
+                (depfile.as_os_str(), "{depfile}".len())
+            } else {
+                //not a placeholder; keep the brace
+                (OsStr::new("{"), 1)
+            };
+            arg.push(replacement);
+            rest = &after[len..];
+        }
+        arg.push(rest);
+        args.push(arg);
+    }
+    args
+}
+
+#[test] fn expand() {
+    let args = expand_template("glslc {input} -o {output} -MF{depfile} {flags} -D{x}",
+                               Path::new("a b.frag"), Path::new("out/a.spv"), Path::new("out/a.d"), &["-O", "-g"]);
+    assert_eq!(args, ["glslc", "a b.frag", "-o", "out/a.spv", "-MFout/a.d", "-O", "-g", "-D{x}"]);
+}
+
+#[cfg(unix)]
+#[test] fn run_template() {
+    use crate::{CompileSettingsBuilder, CompileSystem, PathType, SourceFileStrategy};
+    struct Copy;
+    impl CommandTemplate for Copy {
+        const SOURCE_FILE_EXTENSION: &'static str = "txt";
+        const OUTPUT_EXTENSION: &'static str = "out";
+        const TEMPLATE: &'static str = "cp {flags} {input} {output}";
+        const DEBUG_FLAGS: &'static [&'static str] = &["-f"];
+    }
+    let mut dir = std::env::temp_dir();
+    dir.push("buildkit-run-template");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join("a.txt");
+    std::fs::write(&source, "source").unwrap();
+    let settings = CompileSettingsBuilder::new()
+        .source_strategy(SourceFileStrategy::SourceFiles(vec![source]))
+        .intermediate_path(PathType::Exact(dir.join("out")))
+        .configuration(Configuration::Debug)
+        .finish();
+    let outputs = CompileSystem::<TemplateCompileStep<Copy>>::build(&settings).unwrap();
+    assert_eq!(outputs, [dir.join("out").join("a.out")]);
+    assert_eq!(std::fs::read_to_string(&outputs[0]).unwrap(), "source");
+}
+
+#[cfg(unix)]
+#[test] fn template_mirrors() {
+    use crate::{CompileSettingsBuilder, CompileSystem, PathType, SourceFileStrategy};
+    struct Copy;
+    impl CommandTemplate for Copy {
+        const SOURCE_FILE_EXTENSION: &'static str = "frag";
+        const OUTPUT_EXTENSION: &'static str = "spv";
+        const TEMPLATE: &'static str = "cp {input} {output}";
+    }
+    let mut dir = std::env::temp_dir();
+    dir.push("buildkit-template-mirrors");
+    let _ = std::fs::remove_dir_all(&dir);
+    for sub in ["ui", "world"] {
+        std::fs::create_dir_all(dir.join("src").join(sub)).unwrap();
+        std::fs::write(dir.join("src").join(sub).join("button.frag"), sub).unwrap();
+    }
+    let settings = CompileSettingsBuilder::new()
+        .source_strategy(SourceFileStrategy::SearchFromManifest(vec![dir.join("src")]))
+        .intermediate_path(PathType::Exact(dir.join("out")))
+        .configuration(Configuration::Debug)
+        .finish();
+    let mut outputs = CompileSystem::<TemplateCompileStep<Copy>>::build(&settings).unwrap();
+    outputs.sort();
+    assert_eq!(outputs, [dir.join("out").join("ui").join("button.spv"), dir.join("out").join("world").join("button.spv")]);
+    assert_eq!(std::fs::read_to_string(&outputs[1]).unwrap(), "world");
+}
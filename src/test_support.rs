@@ -0,0 +1,100 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use crate::{BuildError, BuildSettings, BuildSettingsBuilder, CompileContext, CompileSettings, CompileSettingsBuilder, CompileTool, Configuration, LinkContext, LinkTool, PathType, SourceFileStrategy};
+
+///A fresh, empty directory for the named test
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("buildkit-{}", name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+///Writes each file, relative to `dir`, containing its own name.  Returns the full paths.
+pub(crate) fn write_sources(dir: &Path, names: &[&str]) -> Vec<PathBuf> {
+    names.iter().map(|name| {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, name).unwrap();
+        path
+    }).collect()
+}
+
+///Settings for a debug build into `<dir>/out`
+pub(crate) fn compile_settings(dir: &Path, strategy: SourceFileStrategy) -> CompileSettingsBuilder {
+    let mut builder = CompileSettingsBuilder::new();
+    builder.source_strategy(strategy)
+        .intermediate_path(PathType::Exact(dir.join("out")))
+        .configuration(Configuration::Debug);
+    builder
+}
+
+///Settings for linking into `<dir>/product`
+pub(crate) fn build_settings(dir: &Path, compile_settings: CompileSettings) -> BuildSettings {
+    std::fs::create_dir_all(dir.join("product")).unwrap();
+    BuildSettingsBuilder::new()
+        .compile_settings(compile_settings)
+        .product_path(PathType::Exact(dir.join("product")))
+        .finish()
+}
+
+pub(crate) fn set_modified(path: &Path, time: SystemTime) {
+    std::fs::File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+}
+
+///Copies each sourcefile to a mirrored `.out` file, and writes a Makefile dependency file.
+pub(crate) struct CopyCompiler {
+    pub(crate) extension: &'static str,
+    ///Files next to each sourcefile that it also depends on
+    pub(crate) headers: Vec<&'static str>,
+    pub(crate) compiles: AtomicUsize,
+}
+
+impl CopyCompiler {
+    pub(crate) fn new() -> Self {
+        CopyCompiler { extension: "txt", headers: Vec::new(), compiles: AtomicUsize::new(0) }
+    }
+    pub(crate) fn compiles(&self) -> usize {
+        self.compiles.load(Ordering::Relaxed)
+    }
+}
+
+impl CompileTool for CopyCompiler {
+    fn source_file_extension(&self) -> &str { self.extension }
+    fn compile_one(&self, context: &CompileContext) -> Result<PathBuf, BuildError> {
+        self.compiles.fetch_add(1, Ordering::Relaxed);
+        let output = context.intermediate_file("out")?;
+        std::fs::copy(context.source, &output).unwrap();
+        let mut depfile = format!("{}: {}", output.display(), context.source.display());
+        for header in &self.headers {
+            depfile.push_str(&format!(" {}", context.source.with_file_name(header).display()));
+        }
+        std::fs::write(context.dependency_path, depfile).unwrap();
+        Ok(output)
+    }
+}
+
+///Writes the number of object files to the product
+pub(crate) struct CountingLinker {
+    pub(crate) links: AtomicUsize,
+}
+
+impl CountingLinker {
+    pub(crate) fn new() -> Self {
+        CountingLinker { links: AtomicUsize::new(0) }
+    }
+    pub(crate) fn links(&self) -> usize {
+        self.links.load(Ordering::Relaxed)
+    }
+}
+
+impl LinkTool for CountingLinker {
+    fn link_all(&self, context: &LinkContext) -> Result<PathBuf, BuildError> {
+        self.links.fetch_add(1, Ordering::Relaxed);
+        let product = context.output_dir.join(context.product_name);
+        std::fs::write(&product, context.object_files.len().to_string()).unwrap();
+        Ok(product)
+    }
+}
@@ -1,7 +1,11 @@
 use std::path::{Path, PathBuf};
 
 use crate::build_settings::Configuration;
+use crate::error::BuildError;
+use crate::dependency_parser::DependencyFormat;
+use crate::context::{CompileContext, LinkContext};
 use std::ffi::{OsStr};
+use std::marker::PhantomData;
 
 ///Implement this trait to bring in your compiler.
 ///
@@ -10,20 +14,40 @@ pub trait CompileStep {
     ///The extension to scan for.  We will use this to create individual `CompileStep`.
     const SOURCE_FILE_EXTENSION: &'static str;
 
+    ///All the extensions to scan for, if this compiler handles more than one, e.g. `&["vert", "frag", "comp"]`.
+    ///
+    /// Defaults to just [CompileStep::SOURCE_FILE_EXTENSION].
+    const SOURCE_FILE_EXTENSIONS: &'static [&'static str] = &[Self::SOURCE_FILE_EXTENSION];
+
+    ///The format of the dependency file written by [CompileStep::compile_one].  Defaults to [DependencyFormat::Makefile].
+    const DEPENDENCY_FORMAT: DependencyFormat = DependencyFormat::Makefile;
+
+    ///Whether `intermediate_dir` mirrors where each sourcefile was found (see [mirror_intermediate_file]), so sourcefiles with the same
+    /// name in different directories get different directories.  Defaults to `false`, a single directory for every sourcefile.
+    const MIRROR_INTERMEDIATE_DIR: bool = false;
+
     ///Compile one file, placing the output in the intermediate dir.
     ///
     /// # args
     /// * `path`: Path to the source file
     /// * `intermediate_dir`: Output location for object files.  To get a path for storing your object file, consider calling `suggest_intermediate_file`.
+    ///   See also [CompileStep::MIRROR_INTERMEDIATE_DIR].
     /// * `configuration`: Holds build settings
-    /// * `dependency_path`: Output file containing discovered dependencies.  If you know what sourcefiles
-    ///    you consulted during the compile (including headers, etc.) write that info to this file.
-    ///    For more information, see [this documentation](https://www.gnu.org/software/make/manual/html_node/Automatic-Prerequisites.html).
+    /// * `dependency_path`: Output file containing discovered dependencies, unique to this sourcefile.  If you know what sourcefiles
+    ///   you consulted during the compile (including headers, etc.) write that info to this file, in the [CompileStep::DEPENDENCY_FORMAT].
+    ///   For more information, see [this documentation](https://www.gnu.org/software/make/manual/html_node/Automatic-Prerequisites.html).
+    ///   Writing this file is optional.  If you don't, buildkit only tracks the sourcefile itself.  Dependencies that are later deleted
+    ///   cause a recompile.
     /// * `flags`: Compiler flags.
     /// # Returns
     /// * Returns a path to the compiled object file, should be located in the intermediate dir.
     ///
     fn compile_one<'a>(path: &Path,intermediate_dir: &Path, configuration: &Configuration,dependency_path: &Path,flags: impl Iterator<Item=&'a str>) -> PathBuf;
+
+    ///Version of the underlying tool, e.g. the output of `glslc --version`.
+    ///
+    /// When incremental builds are enabled, changing this recompiles everything.  Returns `None` by default.
+    fn tool_version() -> Option<String> { None }
 }
 
 ///Implement this trait to bring in your linker
@@ -31,6 +55,136 @@ pub trait LinkStep {
     fn link_all(object_files: &[PathBuf], out_dir: &Path, lib_name: &str, configuration: &Configuration) -> PathBuf;
 }
 
+///A [CompileStep] that can fail.
+///
+/// Implement this instead of [CompileStep] to report failures as a [BuildError] rather than panicking.
+/// Every [CompileStep] is also a [TryCompileStep].
+pub trait TryCompileStep {
+    ///The extension to scan for.  See [CompileStep::SOURCE_FILE_EXTENSION].
+    const SOURCE_FILE_EXTENSION: &'static str;
+
+    ///All the extensions to scan for.  See [CompileStep::SOURCE_FILE_EXTENSIONS].
+    const SOURCE_FILE_EXTENSIONS: &'static [&'static str] = &[Self::SOURCE_FILE_EXTENSION];
+
+    ///The format of the dependency file.  See [CompileStep::DEPENDENCY_FORMAT].
+    const DEPENDENCY_FORMAT: DependencyFormat = DependencyFormat::Makefile;
+
+    ///Whether the intermediate dir mirrors where each sourcefile was found.  See [CompileStep::MIRROR_INTERMEDIATE_DIR].
+    const MIRROR_INTERMEDIATE_DIR: bool = false;
+
+    ///Compile one file, placing the output in the intermediate dir.  See [CompileStep::compile_one] for the arguments.
+    ///
+    /// On failure, return a [BuildError::Step], usually created from a [crate::StepError].
+    fn try_compile_one<'a>(path: &Path,intermediate_dir: &Path, configuration: &Configuration,dependency_path: &Path,flags: impl Iterator<Item=&'a str>) -> Result<PathBuf, BuildError>;
+
+    ///Version of the underlying tool.  See [CompileStep::tool_version].
+    fn tool_version() -> Option<String> { None }
+}
+
+impl<C: CompileStep> TryCompileStep for C {
+    const SOURCE_FILE_EXTENSION: &'static str = C::SOURCE_FILE_EXTENSION;
+    const SOURCE_FILE_EXTENSIONS: &'static [&'static str] = C::SOURCE_FILE_EXTENSIONS;
+    const DEPENDENCY_FORMAT: DependencyFormat = C::DEPENDENCY_FORMAT;
+    const MIRROR_INTERMEDIATE_DIR: bool = C::MIRROR_INTERMEDIATE_DIR;
+    fn try_compile_one<'a>(path: &Path, intermediate_dir: &Path, configuration: &Configuration, dependency_path: &Path, flags: impl Iterator<Item=&'a str>) -> Result<PathBuf, BuildError> {
+        Ok(C::compile_one(path, intermediate_dir, configuration, dependency_path, flags))
+    }
+    fn tool_version() -> Option<String> { C::tool_version() }
+}
+
+///A [LinkStep] that can fail.
+///
+/// Every [LinkStep] is also a [TryLinkStep].
+pub trait TryLinkStep {
+    fn try_link_all(object_files: &[PathBuf], out_dir: &Path, lib_name: &str, configuration: &Configuration) -> Result<PathBuf, BuildError>;
+}
+
+impl<L: LinkStep> TryLinkStep for L {
+    fn try_link_all(object_files: &[PathBuf], out_dir: &Path, lib_name: &str, configuration: &Configuration) -> Result<PathBuf, BuildError> {
+        Ok(L::link_all(object_files, out_dir, lib_name, configuration))
+    }
+}
+
+///A compiler that can carry its own configuration, such as a tool path or include directories.
+///
+/// This is the instance-based version of [TryCompileStep].  Pass one to [crate::CompileSystem::build_with]
+/// or [crate::BuildSystem::build_with].  Compiles may run on several threads at once, so this must be [Sync].
+///
+/// Any configuration on the instance that affects outputs must be covered by [CompileTool::cache_identity],
+/// or incremental builds and the artifact cache will reuse outputs compiled with the old configuration.
+pub trait CompileTool: Sync {
+    ///The extension to scan for.  See [CompileStep::SOURCE_FILE_EXTENSION].
+    fn source_file_extension(&self) -> &str;
+
+    ///All the extensions to scan for.  See [CompileStep::SOURCE_FILE_EXTENSIONS].
+    ///
+    /// Defaults to just [CompileTool::source_file_extension].
+    fn source_file_extensions(&self) -> Vec<&str> { vec![self.source_file_extension()] }
+
+    ///The format of the file written to [CompileContext::dependency_path].  Defaults to [DependencyFormat::Makefile].
+    ///
+    /// This is ignored if the step calls [CompileContext::report_dependencies].
+    fn dependency_format(&self) -> DependencyFormat { DependencyFormat::Makefile }
+
+    ///Compile one file, placing the output in [CompileContext::output_dir].
+    ///
+    /// Returns a path to the compiled object file.
+    fn compile_one(&self, context: &CompileContext) -> Result<PathBuf, BuildError>;
+
+    ///Version of the underlying tool.  See [CompileStep::tool_version].
+    fn tool_version(&self) -> Option<String> { None }
+
+    ///Identifies this step in incremental builds and the artifact cache (see [crate::CompileSettingsBuilder::cache_dir]), so different steps don't share outputs.
+    /// Changing it recompiles everything.
+    ///
+    /// Defaults to the type name.  If the instance has settings that affect its output, other than flags and [CompileTool::tool_version], include them.
+    fn cache_identity(&self) -> String { std::any::type_name::<Self>().to_owned() }
+}
+
+///A linker that can carry its own configuration.
+///
+/// This is the instance-based version of [TryLinkStep].
+pub trait LinkTool {
+    ///Link [LinkContext::object_files] into a product in [LinkContext::output_dir].
+    ///
+    /// Returns a path to the product.
+    fn link_all(&self, context: &LinkContext) -> Result<PathBuf, BuildError>;
+}
+
+///Adapts a [TryCompileStep] or [TryLinkStep] (including any [CompileStep] or [LinkStep]) into a [CompileTool] or [LinkTool].
+pub struct Static<S> {
+    step: PhantomData<fn() -> S>,
+}
+
+impl<S> Static<S> {
+    pub fn new() -> Self { Static { step: PhantomData } }
+}
+
+impl<S> Default for Static<S> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<S: TryCompileStep> CompileTool for Static<S> {
+    fn source_file_extension(&self) -> &str { S::SOURCE_FILE_EXTENSION }
+    fn source_file_extensions(&self) -> Vec<&str> { S::SOURCE_FILE_EXTENSIONS.to_vec() }
+    fn dependency_format(&self) -> DependencyFormat { S::DEPENDENCY_FORMAT }
+    fn compile_one(&self, context: &CompileContext) -> Result<PathBuf, BuildError> {
+        //static steps don't see the source root, so those that want mirroring get a directory that already mirrors it
+        let intermediate_dir = match context.source_root {
+            Some(root) if S::MIRROR_INTERMEDIATE_DIR => mirror_intermediate_dir(context.source, root, context.output_dir)?,
+            _ => context.output_dir.to_path_buf(),
+        };
+        S::try_compile_one(context.source, &intermediate_dir, &context.configuration, context.dependency_path, context.flags_iter())
+    }
+    fn tool_version(&self) -> Option<String> { S::tool_version() }
+}
+
+impl<S: TryLinkStep> LinkTool for Static<S> {
+    fn link_all(&self, context: &LinkContext) -> Result<PathBuf, BuildError> {
+        S::try_link_all(context.object_files, context.output_dir, context.product_name, &context.configuration)
+    }
+}
+
 /**
 Quick helper function to compute a path for output files (e.g., `-o <somewhere>`).
 
@@ -49,3 +203,43 @@ pub fn suggest_intermediate_file(input_file: &Path, intermediate_dir: PathBuf, f
     new_name.push(file_with_extension);
     new_name
 }
+
+/**
+Like [suggest_intermediate_file], but keeps the path of `input_file` relative to `source_root`, so sourcefiles
+with the same name in different directories get different object files.
+
+For example, `src/ui/button.frag` with the root `src` becomes `<intermediate_dir>/ui/button.<file_extension>`.
+Directories are created as needed.  If `input_file` is not inside `source_root`, this is the same as [suggest_intermediate_file].
+
+Usually you get `source_root` from [CompileContext::source_root], or call [CompileContext::intermediate_file].
+*/
+pub fn mirror_intermediate_file(input_file: &Path, source_root: &Path, intermediate_dir: &Path, file_extension: &OsStr) -> Result<PathBuf, BuildError> {
+    let dir = mirror_intermediate_dir(input_file, source_root, intermediate_dir)?;
+    Ok(suggest_intermediate_file(input_file, dir, file_extension))
+}
+
+///The directory [mirror_intermediate_file] puts files in, which is created if needed.
+pub(crate) fn mirror_intermediate_dir(input_file: &Path, source_root: &Path, intermediate_dir: &Path) -> Result<PathBuf, BuildError> {
+    let mut dir = intermediate_dir.to_path_buf();
+    if let Some(parent) = input_file.strip_prefix(source_root).ok().and_then(|relative| relative.parent()) {
+        //don't let `..` escape the intermediate directory
+        if parent.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+            dir.push(parent);
+        }
+    }
+    std::fs::create_dir_all(&dir).map_err(|error| BuildError::Io { path: dir.clone(), error })?;
+    Ok(dir)
+}
+
+#[test] fn mirror_paths() {
+    let mut dir = std::env::temp_dir();
+    dir.push("buildkit-mirror-paths");
+    let _ = std::fs::remove_dir_all(&dir);
+    let root = Path::new("/project/src");
+    let ext = OsStr::new("spv");
+    assert_eq!(mirror_intermediate_file(&root.join("ui/button.frag"), root, &dir, ext).unwrap(), dir.join("ui").join("button.spv"));
+    assert_eq!(mirror_intermediate_file(&root.join("world/button.frag"), root, &dir, ext).unwrap(), dir.join("world").join("button.spv"));
+    assert!(dir.join("ui").is_dir());
+    assert_eq!(mirror_intermediate_file(&root.join("top.frag"), root, &dir, ext).unwrap(), dir.join("top.spv"));
+    assert_eq!(mirror_intermediate_file(Path::new("/elsewhere/a.frag"), root, &dir, ext).unwrap(), dir.join("a.spv"));
+}
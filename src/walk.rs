@@ -0,0 +1,220 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::BuildError;
+use crate::glob::Glob;
+
+///Ignore files we read when [WalkOptions::ignore_files] is set.
+pub(crate) const IGNORE_FILES: [&str; 2] = [".gitignore", ".buildkitignore"];
+
+///What to do with symbolic links found while searching directories for sourcefiles.
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+pub enum SymlinkPolicy {
+    ///Follow symlinks to files and directories.  A symlink to a directory containing it is reported as [BuildError::SymlinkLoop].
+    #[default]
+    Follow,
+    ///Ignore symlinks.
+    Skip,
+    ///Fail with [BuildError::SymlinkNotAllowed].
+    Error,
+}
+
+///Controls how [dir_walk] searches directories.
+#[derive(Clone,Debug,Default)]
+pub(crate) struct WalkOptions {
+    ///Descend into directories whose names start with `.`
+    pub(crate) include_hidden: bool,
+    ///Skip paths listed in `.gitignore` and `.buildkitignore` files
+    pub(crate) ignore_files: bool,
+    pub(crate) symlinks: SymlinkPolicy,
+    ///How many levels of directories below the base to search.  `None` is unlimited.
+    pub(crate) max_depth: Option<usize>,
+}
+
+///One rule from an ignore file
+#[derive(Debug)]
+struct IgnoreRule {
+    glob: Glob,
+    ///Rule starts with `!`, so it re-includes paths
+    negated: bool,
+    ///Rule ends with `/`, so it only matches directories
+    directory_only: bool,
+}
+
+///Parses a `.gitignore`-style file located in `dir`.
+fn parse_ignore_file(dir: &Path, contents: &str) -> Vec<IgnoreRule> {
+    let mut rules = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') { continue }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let (directory_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        //patterns with a slash are relative to the ignore file; others match at any depth
+        let pattern = match line.strip_prefix('/') {
+            Some(anchored) => anchored.to_owned(),
+            None if line.contains('/') => line.to_owned(),
+            None => format!("**/{}", line),
+        };
+        rules.push(IgnoreRule { glob: Glob::new(dir, &pattern), negated, directory_only });
+    }
+    rules
+}
+
+fn read_ignore_files(dir: &Path, watched: &mut Vec<PathBuf>) -> Vec<IgnoreRule> {
+    let mut rules = Vec::new();
+    for name in IGNORE_FILES {
+        let path = dir.join(name);
+        if let Ok(contents) = std::fs::read_to_string(&path) {
+            rules.extend(parse_ignore_file(dir, &contents));
+            watched.push(path);
+        }
+    }
+    rules
+}
+
+///Whether the last rule matching `path` ignores it.  Rules from deeper directories come later, so they win.
+fn is_ignored(rules: &[Vec<IgnoreRule>], path: &Path, is_dir: bool) -> bool {
+    let mut ignored = false;
+    for rule in rules.iter().flatten() {
+        if (is_dir || !rule.directory_only) && rule.glob.matches(path) {
+            ignored = !rule.negated;
+        }
+    }
+    ignored
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with('.'))
+}
+
+///Walks a directory, looking for sourcefiles that pass `filter`
+///
+/// Returns its output in its argument, because it makes the memory
+/// faster for recursion.  `base`, and other files that decided what we found, like ignore files, are added to `watched`.
+pub fn dir_walk(base: &Path, filter: &dyn Fn(&Path) -> bool, options: &WalkOptions, output: &mut Vec<PathBuf>, watched: &mut Vec<PathBuf>) -> Result<(), BuildError> {
+    let mut rules = Vec::new();
+    if options.ignore_files {
+        //like git, ignore files in parent directories apply, up to the root of the repository
+        let mut ancestors = Vec::new();
+        for ancestor in base.ancestors().skip(1) {
+            ancestors.push(ancestor);
+            if ancestor.join(".git").exists() {
+                //outermost first, so deeper rules win
+                for ancestor in ancestors.iter().rev() {
+                    rules.push(read_ignore_files(ancestor, watched));
+                }
+                break
+            }
+        }
+    }
+    //cargo watches a directory by scanning everything in it, which notices added and removed files
+    watched.push(base.to_path_buf());
+    let mut stack = Vec::new();
+    walk(base, filter, options, &mut rules, &mut stack, output, watched)
+}
+
+///`stack` holds the canonical paths of the directories we are inside, for detecting symlink loops
+fn walk(base: &Path, filter: &dyn Fn(&Path) -> bool, options: &WalkOptions, rules: &mut Vec<Vec<IgnoreRule>>, stack: &mut Vec<PathBuf>, output: &mut Vec<PathBuf>, watched: &mut Vec<PathBuf>) -> Result<(), BuildError> {
+    let io_error = |path: &Path| { let path = path.to_path_buf(); move |error| BuildError::Io { path, error } };
+    let canonical = std::fs::canonicalize(base).map_err(io_error(base))?;
+    if stack.contains(&canonical) {
+        return Err(BuildError::SymlinkLoop { path: base.to_path_buf() })
+    }
+    stack.push(canonical);
+    if options.ignore_files {
+        rules.push(read_ignore_files(base, watched));
+    }
+    for item in std::fs::read_dir(base).map_err(io_error(base))? {
+        let item = item.map_err(io_error(base))?;
+        let path = item.path();
+        if item.file_type().map_err(io_error(&path))?.is_symlink() {
+            match options.symlinks {
+                SymlinkPolicy::Follow => {}
+                SymlinkPolicy::Skip => continue,
+                SymlinkPolicy::Error => return Err(BuildError::SymlinkNotAllowed { path }),
+            }
+        }
+        if path.is_dir() {
+            if !options.include_hidden && is_hidden(&path) { continue }
+            if is_ignored(rules, &path, true) { continue }
+            //the base is depth 0
+            if options.max_depth.is_some_and(|max| stack.len() > max) { continue }
+            walk(&path, filter, options, rules, stack, output, watched)?;
+        }
+        else if path.is_file() { //I'm not 100% sure what other options there are, but ok
+            if is_ignored(rules, &path, false) { continue }
+            if filter(&path) {
+                output.push(path);
+            }
+        }
+    }
+    if options.ignore_files {
+        rules.pop();
+    }
+    stack.pop();
+    Ok(())
+}
+
+///Whether the path has one of the extensions
+pub fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension().is_some_and(|e| extensions.iter().any(|extension| e == OsStr::new(extension)))
+}
+
+#[test] fn ignore_files() {
+    let mut dir = std::env::temp_dir();
+    dir.push("buildkit-ignore-files");
+    let _ = std::fs::remove_dir_all(&dir);
+    for sub in ["src/generated", "src/ui", "src/.cache", "target"] {
+        std::fs::create_dir_all(dir.join(sub)).unwrap();
+    }
+    std::fs::create_dir_all(dir.join(".git")).unwrap();
+    for file in ["src/a.frag", "src/generated/b.frag", "src/ui/c.frag", "src/ui/copy.frag", "src/ui/keep.frag", "src/.cache/d.frag", "target/e.frag"] {
+        std::fs::write(dir.join(file), "").unwrap();
+    }
+    std::fs::write(dir.join(".gitignore"), "# build output\n/target\nsrc/generated/\n").unwrap();
+    std::fs::write(dir.join("src/ui/.buildkitignore"), "copy*.frag\n*.frag\n!keep.frag\n").unwrap();
+
+    let find = |options: &WalkOptions| {
+        let mut found = Vec::new();
+        dir_walk(&dir, &|p| has_extension(p, &["frag"]), options, &mut found, &mut Vec::new()).unwrap();
+        let mut found: Vec<_> = found.iter().map(|p| p.strip_prefix(&dir).unwrap().to_str().unwrap().replace('\\', "/")).collect();
+        found.sort();
+        found
+    };
+    assert_eq!(find(&WalkOptions::default()), ["src/a.frag", "src/generated/b.frag", "src/ui/c.frag", "src/ui/copy.frag", "src/ui/keep.frag", "target/e.frag"]);
+    assert_eq!(find(&WalkOptions { ignore_files: true, include_hidden: true, ..Default::default() }), ["src/.cache/d.frag", "src/a.frag", "src/ui/keep.frag"]);
+    let mut watched = Vec::new();
+    dir_walk(&dir.join("src"), &|_| true, &WalkOptions { ignore_files: true, ..Default::default() }, &mut Vec::new(), &mut watched).unwrap();
+    assert_eq!(watched, [dir.join(".gitignore"), dir.join("src"), dir.join("src/ui/.buildkitignore")]);
+}
+
+#[cfg(unix)]
+#[test] fn symlinks() {
+    let mut dir = std::env::temp_dir();
+    dir.push("buildkit-symlinks");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("a/b/c")).unwrap();
+    std::fs::write(dir.join("top.frag"), "").unwrap();
+    std::fs::write(dir.join("a/b/c/deep.frag"), "").unwrap();
+    std::os::unix::fs::symlink(dir.join("top.frag"), dir.join("a/linked.frag")).unwrap();
+
+    let find = |options: &WalkOptions| {
+        let mut found = Vec::new();
+        dir_walk(&dir, &|p| has_extension(p, &["frag"]), options, &mut found, &mut Vec::new()).map(|_| found.len())
+    };
+    assert_eq!(find(&WalkOptions::default()).unwrap(), 3);
+    assert_eq!(find(&WalkOptions { symlinks: SymlinkPolicy::Skip, ..Default::default() }).unwrap(), 2);
+    assert!(matches!(find(&WalkOptions { symlinks: SymlinkPolicy::Error, ..Default::default() }), Err(BuildError::SymlinkNotAllowed { .. })));
+    assert_eq!(find(&WalkOptions { max_depth: Some(0), ..Default::default() }).unwrap(), 1);
+    assert_eq!(find(&WalkOptions { max_depth: Some(1), ..Default::default() }).unwrap(), 2);
+
+    std::os::unix::fs::symlink(&dir, dir.join("a/b/loop")).unwrap();
+    assert!(matches!(find(&WalkOptions::default()), Err(BuildError::SymlinkLoop { .. })));
+    assert_eq!(find(&WalkOptions { symlinks: SymlinkPolicy::Skip, ..Default::default() }).unwrap(), 2);
+}